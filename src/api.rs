@@ -1,342 +1,853 @@
 use std::{
     any::type_name,
-    collections::HashMap,
-    error::Error,
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
     net::{Ipv4Addr, Ipv6Addr},
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::Arc,
+    time::SystemTime,
 };
 
 use crate::{
-    config::{self, Authorization, SearchRule, Zone},
-    records::{ListResponse, PatchResponse, Record, RecordResponse, TypeSpecificData},
+    client::{Clients, CloudflareClient},
+    config::{
+        Config, EnsureRecord, FailedFamilies, InterfaceSource, IpRecordType, IpService,
+        Ipv6Rewrite, RecordFields, RecordSettings, SearchRule, Strategy, Zone,
+    },
+    error::Error,
+    interface,
+    misc_serialization::MaxLenString,
+    plan::{plan_ensure, plan_record, Action, ChangeKind, Plan, SkipReason},
+    records::{
+        BatchDelete, BatchPatch, BatchRequest, RecordPatch, RecordRequest, RecordResponse,
+        TypeSpecificData,
+    },
+    report::{RecordListing, RunSummary, ZoneStatus, ZoneSummary},
+    state::{RecordState, State, ZoneState},
+    template::{self, TemplateValues},
 };
 use futures::{future::join_all, join};
-use reqwest::{Method, RequestBuilder, StatusCode};
-
-fn authenticate_request(req: RequestBuilder, auth: &Authorization) -> RequestBuilder {
-    match auth {
-        Authorization::BearerAuth(token) => req.bearer_auth(token),
-        Authorization::ApiKey(api_key) => {
-            let (key, value) = api_key.get_auth_header_tuple();
-            req.header(key, value)
-        }
-    }
+use reqwest::StatusCode;
+
+/// A listed record with the settings of the rules that matched it
+#[derive(Debug)]
+pub struct MatchedRecord {
+    pub record: RecordResponse,
+    pub settings: RecordSettings,
 }
 
-async fn list_records_for_rule(
-    client_arc: Arc<reqwest::Client>,
-    records: Arc<Mutex<HashMap<String, RecordResponse>>>,
-    i: usize,
-    rule: &SearchRule,
+pub async fn list_records(
     zone: &Zone,
-) -> Result<u32, Box<dyn Error + Sync + Send>> {
-    let url_params = match serde_url_params::to_string(&rule) {
-        Ok(v) => v,
-        Err(e) => Err(format!(
-            "(Rule {i}): Search rule could not be serialized into url parameters: {e}",
-        ))?,
-    };
+    client: &CloudflareClient,
+) -> Result<HashMap<String, MatchedRecord>, Error> {
+    // Records that have to exist are looked up by name and type, as the search rules might not match them
+    let ensure_rules: Vec<SearchRule> = zone.ensure.iter().map(|e| e.search_rule()).collect();
+    let rules: Vec<&SearchRule> = zone.search.iter().chain(&ensure_rules).collect();
+    let results = join_all(
+        rules
+            .iter()
+            .map(|rule| client.list_records(zone.id(), rule)),
+    )
+    .await;
+
+    let mut records = HashMap::<String, MatchedRecord>::with_capacity(zone.search.len() * 5);
+    // Records are only complete if every rule could be listed, so any error fails the whole zone
+    for (i, (rule, result)) in rules.iter().zip(results).enumerate() {
+        let rule_records = result.inspect_err(|e| {
+            log::debug!(
+                "(\"{}\"): (Rule {i}): Error while listing records: {e}",
+                zone.display_name()
+            )
+        })?;
+        if rule_records.is_empty() && i < zone.search.len() {
+            log::warn!(
+                "(\"{}\"): (Rule {i}): No records returned for search rule",
+                zone.display_name()
+            );
+        }
+        let settings = rule.settings();
+        let mut new_records = 0;
+        for record in rule_records {
+            match records.entry(record.id.to_string()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(MatchedRecord {
+                        record,
+                        settings: settings.clone(),
+                    });
+                    new_records += 1;
+                }
+                // The first rule with a setting decides it for records matched by several rules
+                Entry::Occupied(mut entry) => {
+                    if !entry.get_mut().settings.merge(settings.clone()) {
+                        log::warn!(
+                            "(\"{}\"): (Rule {i}): ({}): Record already matched by a rule with other settings, ignoring the conflicting settings of this rule",
+                            zone.display_name(),
+                            record.name
+                        );
+                    }
+                }
+            }
+        }
+        log::debug!(
+            "(\"{}\"): (Rule {i}): Got {} new records from record list",
+            zone.display_name(),
+            new_records
+        );
+    }
+    // Fields set for the whole zone apply where no rule sets them
+    for matched in records.values_mut() {
+        matched.settings.fields.merge(zone.set.clone());
+    }
 
-    let url = format!(
-        "https://api.cloudflare.com/client/v4/zones/{}/dns_records?{}",
-        zone.identifier.0, url_params
-    );
+    Ok(records)
+}
 
-    let mut request = client_arc
-        .clone()
-        .request(Method::GET, url)
-        .header("Content-Type", "application/json");
+/// Addresses a record is set to: the detected addresses rewritten by the rule that matched it,
+/// with the fallbacks of the zone for missing address families
+fn record_addresses(
+    zone: &Zone,
+    ipv6_rewrite: Option<&Ipv6Rewrite>,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    failed: FailedFamilies,
+) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    zone.addresses(Ipv6Rewrite::apply_to(ipv6_rewrite, addresses), failed)
+}
 
-    request = authenticate_request(request, &zone.auth);
+pub fn address_tuple_to_string(addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>)) -> String {
+    match addresses {
+        (None, None) => "no addresses".to_owned(),
+        (None, Some(v6)) => format!("{} (IPv6)", v6),
+        (Some(v4), None) => format!("{} (IPv4)", v4),
+        (Some(v4), Some(v6)) => {
+            format!("both {} (IPv4) and {} (IPv6)", v4, v6)
+        }
+    }
+}
 
-    let response = request.send().await?;
+fn ip_version_name<T>() -> &'static str {
+    match type_name::<T>() {
+        "core::net::ip_addr::Ipv4Addr" => "IPv4",
+        "core::net::ip_addr::Ipv6Addr" => "IPv6",
+        t => t,
+    }
+}
 
-    let status = response.status();
-    let text = response.text().await?;
+async fn get_ip_address<T: FromStr>(url: &str, client: &reqwest::Client) -> Result<T, Error>
+where
+    <T as FromStr>::Err: Display,
+{
+    let r = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| Error::IpDetection(format!("Error while sending get request: {e}")))?;
+    match r.status() {
+        StatusCode::OK => {
+            let txt = r
+                .text()
+                .await
+                .map_err(|e| Error::IpDetection(format!("Error while reading response: {e}")))?;
+            txt.trim().parse::<T>().map_err(|e| {
+                Error::IpDetection(format!(
+                    "Error while parsing response \"{}\": {e}",
+                    txt.trim()
+                ))
+            })
+        }
+        code => Err(Error::IpDetection(format!("Received status code {code}"))),
+    }
+}
 
-    let result: ListResponse = match status {
-        StatusCode::OK => serde_json::from_str(&text)?,
-        code => Err(format!(
-            "(Rule {i}): Response for list records request is of code: {}; Text: {}",
-            code, text
-        ))?,
+/// Gets an address from the configured services, combining their results according to the strategy
+async fn detect_ip_address<T>(
+    service: Option<&IpService>,
+    client: &reqwest::Client,
+) -> Result<Option<T>, Error>
+where
+    T: FromStr + PartialEq + Display + Copy,
+    <T as FromStr>::Err: Display,
+{
+    let service = match service {
+        Some(s) => s,
+        None => return Ok(None),
     };
-    if result.result.is_empty() {
-        Err(format!("(Rule {i}): No records returned for search rule"))?;
+    let ip_version = ip_version_name::<T>();
+    let urls = service.urls();
+    if urls.is_empty() {
+        return Err(Error::IpDetection(format!(
+            "No {ip_version} services configured"
+        )));
     }
+    log::info!("Getting {ip_version} address");
 
-    let mut new_records = 0;
-    {
-        let mut record_lock = records.try_lock().unwrap();
-        for record in result.result {
-            if record_lock.insert(record.id.to_string(), record).is_none() {
-                new_records += 1;
+    if service.strategy() == Strategy::first_success {
+        for url in urls {
+            match get_ip_address::<T>(url, client).await {
+                Ok(a) => return Ok(Some(a)),
+                Err(e) => log::warn!("({url}): Could not get {ip_version} address: {e}"),
             }
         }
+        log::error!("None of the {ip_version} services returned an address");
+        return Err(Error::IpDetection(format!(
+            "No {ip_version} address returned"
+        )));
     }
 
-    Ok(new_records)
-}
+    let results = join_all(urls.iter().map(|url| get_ip_address::<T>(url, client))).await;
 
-pub async fn list_records(
-    zone: &config::Zone,
-    client_arc: Arc<reqwest::Client>,
-) -> Result<HashMap<String, RecordResponse>, Box<dyn Error + Sync + Send>> {
-    let mut futures = Vec::new();
-
-    let records = Arc::new(Mutex::new(
-        HashMap::<String, RecordResponse>::with_capacity(zone.search.len() * 5),
-    ));
-
-    for (i, rule) in zone.search.iter().enumerate() {
-        futures.push(list_records_for_rule(
-            client_arc.clone(),
-            records.clone(),
-            i,
-            rule,
-            zone,
-        ));
+    // Addresses in the order they were first returned, with the services that returned them
+    let mut votes: Vec<(T, Vec<&str>)> = Vec::new();
+    for (url, result) in urls.iter().zip(results) {
+        match result {
+            Ok(a) => match votes.iter_mut().find(|(v, _)| *v == a) {
+                Some((_, voters)) => voters.push(url),
+                None => votes.push((a, vec![url])),
+            },
+            Err(e) => log::warn!("({url}): Could not get {ip_version} address: {e}"),
+        }
+    }
+    if votes.len() > 1 {
+        let opinions: Vec<String> = votes
+            .iter()
+            .map(|(a, voters)| format!("{a} ({})", voters.join(", ")))
+            .collect();
+        log::warn!("{ip_version} services disagree: {}", opinions.join("; "));
     }
 
-    let results = join_all(futures).await;
-    for future_result in results {
-        match future_result {
-            Ok(l) => log::debug!(
-                "(\"{}\"): Got {} new records from record list",
-                zone.identifier,
-                l
-            ),
-            Err(e) => log::error!(
-                "(\"{}\"): Error while listing records: {}",
-                zone.identifier,
-                e
-            ),
+    let required = service.required_agreement();
+    // On a tie, the address returned by the service listed first wins. `max_by_key` returns the
+    // last of equal elements, so the votes are searched in reverse.
+    match votes
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, voters)| voters.len())
+    {
+        Some((a, voters)) if voters.len() >= required => Ok(Some(a)),
+        Some((_, voters)) => {
+            log::error!(
+                "Only {} of {} {ip_version} services agreed, {} required",
+                voters.len(),
+                urls.len(),
+                required
+            );
+            Err(Error::IpDetection(format!(
+                "No {ip_version} quorum reached"
+            )))
+        }
+        None => {
+            log::error!("None of the {ip_version} services returned an address");
+            Err(Error::IpDetection(format!(
+                "No {ip_version} address returned"
+            )))
         }
     }
-
-    Ok(Arc::into_inner(records).unwrap().into_inner().unwrap())
 }
 
-pub async fn patch_ip_record_address(
-    zone: Arc<config::Zone>,
-    record: Arc<dyn Record + Send + Sync>,
-    client: Arc<reqwest::Client>,
-    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
-) -> Result<PatchResponse, Box<dyn Error + Send + Sync>> {
-    if addresses.0.is_none() && addresses.0.is_none() {
-        return Err("No addresses provided".into());
-    }
+type InterfaceReader<T> = fn(&InterfaceSource) -> Result<Option<T>, Error>;
 
-    let addr = match &record.get_type_data() {
-        TypeSpecificData::A { .. } => match addresses.0 {
-            Some(a) => Ok(a.to_string()),
-            None => Err("No ipv4 address found to patch A record"),
-        },
-        TypeSpecificData::AAAA { .. } => match addresses.1 {
-            Some(a) => Ok(a.to_string()),
-            None => Err("No ipv6 address found to patch AAAA record"),
-        },
-        _ => Err("Provided record is not an ip record"),
-    }?;
-
-    let record_id = match record.get_id() {
-        Some(id) => Ok(&id.0),
-        None => Err("Record does not have an id"),
-    }?;
-    let url = format!(
-        "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-        zone.identifier, record_id
-    );
-    let mut req = client.patch(url).header("Content-Type", "application/json");
-
-    req = authenticate_request(req, &zone.auth);
-    let mut map = HashMap::new();
-    map.insert("content", addr);
-    req = req.json(&map);
-
-    let response = req.send().await?;
-
-    let status = response.status();
-    let text = response.text().await?;
+/// Gets an address from the interface if configured, falling back to the services.
+/// Returns `None` if neither is configured or the interface has no usable address and no services are configured.
+async fn detect_family<T>(
+    interface: Option<&InterfaceSource>,
+    read_interface: InterfaceReader<T>,
+    service: Option<&IpService>,
+    client: &reqwest::Client,
+) -> Result<Option<T>, Error>
+where
+    T: FromStr + PartialEq + Display + Copy,
+    <T as FromStr>::Err: Display,
+{
+    let ip_version = ip_version_name::<T>();
+    if let Some(interface) = interface {
+        log::info!(
+            "Getting {ip_version} address from interface {}",
+            interface.name
+        );
+        match read_interface(interface) {
+            Ok(Some(a)) => return Ok(Some(a)),
+            Ok(None) if service.is_some() => log::warn!(
+                "No usable {ip_version} address found on {}, falling back to {ip_version} services",
+                interface.name
+            ),
+            Ok(None) => {
+                log::warn!("No usable {ip_version} address found on {}", interface.name);
+                return Ok(None);
+            }
+            Err(e) if service.is_some() => {
+                log::warn!("{e}, falling back to {ip_version} services")
+            }
+            Err(e) => {
+                log::error!("{e}");
+                return Err(e);
+            }
+        }
+    }
+    detect_ip_address(service, client).await
+}
 
-    match status {
-        StatusCode::OK => Ok(serde_json::from_str(&text)?),
-        code => Err(format!(
-            "Error {} while patching record {}: {}",
-            code,
-            &record.get_name().0,
-            text
+/// The result of detecting the address of each family. A family whose detection failed is kept
+/// apart from one that is missing, as only the records of missing families are deleted or replaced.
+#[derive(Debug)]
+pub struct DetectedAddresses {
+    pub ipv4: Result<Option<Ipv4Addr>, Error>,
+    pub ipv6: Result<Option<Ipv6Addr>, Error>,
+}
+impl DetectedAddresses {
+    /// The addresses that were detected
+    pub fn addresses(&self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        (
+            self.ipv4.as_ref().ok().copied().flatten(),
+            self.ipv6.as_ref().ok().copied().flatten(),
         )
-        .into()),
     }
-}
-pub fn address_tuple_to_string(addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>)) -> String {
-    match addresses {
-        (None, None) => "no addresses".to_owned(),
-        (None, Some(v6)) => format!("{} (IPv6)", v6),
-        (Some(v4), None) => format!("{} (IPv4)", v4),
-        (Some(v4), Some(v6)) => {
-            format!("both {} (IPv4) and {} (IPv6)", v4, v6)
+
+    pub fn failed(&self) -> FailedFamilies {
+        FailedFamilies {
+            ipv4: self.ipv4.is_err(),
+            ipv6: self.ipv6.is_err(),
         }
     }
 }
 
-async fn get_ip_address<T: FromStr>(
-    url_opt: Option<String>,
-    client: Arc<reqwest::Client>,
-) -> Result<Option<T>, Box<dyn Error + Sync + Send>>
-where
-    <T as FromStr>::Err: Error + Sync + Send,
-    <T as FromStr>::Err: 'static,
-{
-    if let Some(url) = url_opt {
-        let ip_version = match type_name::<T>() {
-            "core::net::ip_addr::Ipv4Addr" => "IPv4",
-            "core::net::ip_addr::Ipv6Addr" => "IPv6",
-            t => t,
-        };
-        log::info!("Getting {ip_version} address");
-        let r = client.get(url).send().await.or_else(|e| {
-            log::error!("Error while sending get request for {ip_version}: {e}");
-            Err(e)
-        })?;
-        match r.status() {
-            StatusCode::OK => Ok(Some({
-                let txt = r.text().await.or_else(|e| {
-                    log::error!("Error while reading response for {ip_version}: {e}");
-                    Err(e)
-                })?;
-                txt.parse::<T>().or_else(|e| {
-                    log::error!("Error while parsing response for {ip_version}: {e}");
-                    Err(e)
-                })?
-            })),
-            code => {
-                log::error!(
-                    "Received status code {} while getting {} address",
-                    code,
-                    ip_version
-                );
-                Err("Bad response code".into())
-            }
+/// Detects the addresses of both families, failing only if no address at all was detected.
+/// The error of a single family is logged and kept in the result.
+pub async fn get_ip_addresses(
+    conf: &Config,
+    client: &reqwest::Client,
+) -> Result<DetectedAddresses, Error> {
+    let (ipv4, ipv6) = join!(
+        detect_family::<Ipv4Addr>(
+            conf.ipv4_interface.as_ref(),
+            interface::get_ipv4_address,
+            conf.ipv4_service.as_ref(),
+            client
+        ),
+        detect_family::<Ipv6Addr>(
+            conf.ipv6_interface.as_ref(),
+            interface::get_ipv6_address,
+            conf.ipv6_service.as_ref(),
+            client
+        )
+    );
+
+    let detected = DetectedAddresses { ipv4, ipv6 };
+    match (&detected.ipv4, &detected.ipv6) {
+        (Ok(None) | Err(_), Ok(None) | Err(_)) => {
+            log::error!("Neither an IPv4 nor an IPv6 address could be determined");
+            Err(Error::IpDetection("No addresses returned".to_owned()))
         }
-    } else {
-        Ok(None)
+        (Err(e), _) => {
+            log::warn!("Keeping IPv4 records unchanged: {e}");
+            Ok(detected)
+        }
+        (_, Err(e)) => {
+            log::warn!("Keeping IPv6 records unchanged: {e}");
+            Ok(detected)
+        }
+        _ => Ok(detected),
     }
 }
 
-pub async fn get_ip_addresses(
-    ipv4_service_url: Option<String>,
-    ipv6_service_url: Option<String>,
-    client: Arc<reqwest::Client>,
-) -> Result<(Option<Ipv4Addr>, Option<Ipv6Addr>), Box<dyn Error>> {
-    let r = join!(
-        get_ip_address::<Ipv4Addr>(ipv4_service_url, client.clone()),
-        get_ip_address::<Ipv6Addr>(ipv6_service_url, client)
-    );
+/// Outcome of patching a single zone
+#[derive(Default, Debug)]
+pub struct ZoneResult {
+    pub patched: u16,
+    pub created: u16,
+    pub deleted: u16,
+    /// Errors of the records that could not be changed
+    pub errors: Vec<Error>,
+    /// Records that are up to date after patching, keyed by record id
+    pub records: HashMap<String, RecordState>,
+}
 
-    match r {
-        (Err(_), Err(_)) => {
-            log::error!("Both IPv4 and IPv6 addresses errored");
-            Err("No addresses returned".into())
+/// Request creating the record, with the fields set for the zone and its comment template rendered
+fn create_request(
+    ensure: &EnsureRecord,
+    zone_fields: &RecordFields,
+    content: String,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+) -> Result<RecordRequest, SkipReason> {
+    let values = TemplateValues {
+        ipv4: addresses.0,
+        ipv6: addresses.1,
+        hostname: template::hostname(),
+        name: &ensure.name.0,
+        now: SystemTime::now(),
+    };
+    let mut fields = ensure.fields();
+    fields.merge(zone_fields.clone());
+    let comment = fields
+        .comment
+        .as_deref()
+        .map(|c| template::render(c, &values))
+        .transpose()?;
+    let proxied = fields.proxied;
+    let type_data = match ensure.r#type {
+        IpRecordType::A => TypeSpecificData::A { content, proxied },
+        IpRecordType::AAAA => TypeSpecificData::AAAA { content, proxied },
+    };
+    Ok(RecordRequest {
+        type_data,
+        name: ensure.name.to_string(),
+        comment,
+        tags: fields.tags,
+        ttl: fields.ttl,
+    })
+}
+
+/// A change to a single record, as sent to the API
+enum Change {
+    Patch {
+        record_id: String,
+        patch: RecordPatch,
+    },
+    Create(RecordRequest),
+    Delete {
+        record_id: String,
+    },
+}
+
+/// Sends the change, returning the record after the change unless it was deleted
+async fn apply_change(
+    client: Arc<CloudflareClient>,
+    zone_id: String,
+    zone_name: String,
+    name: String,
+    change: Change,
+) -> (ChangeKind, Result<Option<RecordResponse>, Error>) {
+    let (kind, result) = match change {
+        Change::Patch { record_id, patch } => {
+            let r = client.patch_record(&zone_id, &record_id, &patch).await;
+            (ChangeKind::Patch, r.map(Some))
+        }
+        Change::Create(request) => {
+            let r = client.create_record(&zone_id, &request).await;
+            (ChangeKind::Create, r.map(Some))
         }
-        (r4, r6) => Ok((r4.unwrap_or(None), r6.unwrap_or(None))),
+        Change::Delete { record_id } => {
+            let r = client.delete_record(&zone_id, &record_id).await;
+            (ChangeKind::Delete, r.map(|()| None))
+        }
+    };
+    let (verb, done) = match kind {
+        ChangeKind::Patch => ("patching", "patched"),
+        ChangeKind::Create => ("creating", "created"),
+        ChangeKind::Delete => ("deleting", "deleted"),
+    };
+    match &result {
+        Ok(_) => log::info!("(\"{zone_name}\"): ({name}): Successfully {done} record"),
+        Err(e) => log::error!("(\"{zone_name}\"): ({name}): Error while {verb} record: {e}"),
     }
+    (kind, result)
 }
 
 pub async fn patch_zone(
-    zone: Zone,
-    client_arc: Arc<reqwest::Client>,
+    zone: &Zone,
+    client: Arc<CloudflareClient>,
     addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
-) -> Result<u16, Box<dyn Error>> {
-    let id = zone.identifier.clone();
-
+    failed: FailedFamilies,
+) -> Result<ZoneResult, Error> {
+    let id = zone.display_name();
     log::info!("(\"{id}\"): Listing records");
-    let mut response_map = match list_records(&zone, client_arc.clone()).await {
-        Ok(v) => v,
-        Err(e) => Err(format!("Could not list records for zone \"{}\": {}", id, e))?,
-    };
+    let mut response_map = list_records(zone, &client).await?;
 
     log::info!("(\"{id}\"): Received {} records", response_map.len());
     log::debug!("(\"{id}\"): Responses: {:?}", response_map);
 
-    let zone_arc = Arc::new(zone);
-
-    let mut futures = Vec::with_capacity(response_map.len());
+    let mut changes = Vec::with_capacity(response_map.len() + zone.ensure.len());
+    for ensure in &zone.ensure {
+        let ensure_addresses =
+            record_addresses(zone, ensure.ipv6_rewrite().as_ref(), addresses, failed);
+        let records = response_map.values().map(|m| &m.record);
+        let request = match plan_ensure(ensure, records, ensure_addresses) {
+            Some(Action::Create { content }) => {
+                create_request(ensure, &zone.set, content, ensure_addresses)
+            }
+            Some(Action::Skip(reason)) => Err(reason),
+            // Found records are planned below
+            _ => continue,
+        };
+        match request {
+            Ok(request) => changes.push((ensure.name.to_string(), Change::Create(request))),
+            Err(reason) => {
+                log::warn!(
+                    "(\"{id}\"): ({}): {reason}, not creating record",
+                    ensure.name
+                );
+            }
+        }
+    }
 
-    log::info!("(\"{id}\"): Patching records");
-    for (_record_id, record) in response_map.drain() {
-        if match &record.type_data {
-            TypeSpecificData::A { content, .. } => match addresses.0 {
-                Some(v) => *content == v.to_string(),
-                None => {
-                    log::warn!("(\"{id}\"): ({}): Cannot update record as no IPv4 address is provided, skipping",record.name);
-                    continue;
+    let mut result = ZoneResult::default();
+    for (record_id, MatchedRecord { record, settings }) in response_map.drain() {
+        let addresses = record_addresses(zone, settings.ipv6_rewrite.as_ref(), addresses, failed);
+        let change = match plan_record(&record, addresses, zone.missing_family, failed, &settings) {
+            Action::Patch { content, fields } => {
+                let content = content.map(|c| {
+                    record.type_data.templated_patch(c).expect(
+                        "Only records with a templated value should have their content patched",
+                    )
+                });
+                Change::Patch {
+                    record_id,
+                    patch: RecordPatch {
+                        ttl: fields.ttl,
+                        proxied: fields.proxied,
+                        comment: fields.comment,
+                        tags: fields.tags,
+                        ..content.unwrap_or_default()
+                    },
                 }
-            },
-            TypeSpecificData::AAAA { content, .. } => match addresses.1 {
-                Some(v) => *content == v.to_string(),
-                None => {
-                    log::warn!("(\"{id}\"): ({}): Cannot update record as no IPv6 address is provided, skipping",record.name);
-                    continue;
+            }
+            Action::Delete => Change::Delete { record_id },
+            Action::Create { .. } => {
+                unreachable!("Records that exist are never planned to be created")
+            }
+            Action::Skip(reason) => {
+                log::warn!("(\"{id}\"): ({}): {reason}, skipping", record.name);
+                if reason == SkipReason::Unchanged {
+                    result.records.insert(record_id, RecordState::from(&record));
                 }
-            },
-            _ => {
-                log::warn!(
-                    "(\"{id}\"): ({}): Record is not an IP record, skipping",
-                    record.name
-                );
                 continue;
             }
-        } {
-            log::warn!(
-                "(\"{id}\"): ({}): Content has not changed, skipping",
-                record.name
+        };
+        changes.push((record.name.to_string(), change));
+    }
+
+    if zone.atomic {
+        apply_batch(&client, zone, changes, &mut result).await;
+    } else {
+        apply_changes(client, zone, changes, &mut result).await;
+    }
+
+    Ok(result)
+}
+
+/// Sends every change on its own, so changes that fail do not keep the others from being made
+async fn apply_changes(
+    client: Arc<CloudflareClient>,
+    zone: &Zone,
+    changes: Vec<(String, Change)>,
+    result: &mut ZoneResult,
+) {
+    let id = zone.display_name();
+    log::info!("(\"{id}\"): Applying {} changes", changes.len());
+    let tasks = changes.into_iter().map(|(name, change)| {
+        tokio::spawn(apply_change(
+            client.clone(),
+            zone.id().to_owned(),
+            id.to_owned(),
+            name,
+            change,
+        ))
+    });
+    for r in join_all(tasks).await {
+        let (kind, r) = r.expect("Change task should not panic");
+        match r {
+            Ok(record) => {
+                match kind {
+                    ChangeKind::Patch => result.patched += 1,
+                    ChangeKind::Create => result.created += 1,
+                    ChangeKind::Delete => result.deleted += 1,
+                }
+                if let Some(record) = record {
+                    result
+                        .records
+                        .insert(record.id.to_string(), RecordState::from(&record));
+                }
+            }
+            Err(e) => result.errors.push(e),
+        }
+    }
+}
+
+/// Sends all changes in one batch request, which the API applies either completely or not at all
+async fn apply_batch(
+    client: &CloudflareClient,
+    zone: &Zone,
+    changes: Vec<(String, Change)>,
+    result: &mut ZoneResult,
+) {
+    let id = zone.display_name();
+    let mut batch = BatchRequest::default();
+    for (_, change) in changes {
+        match change {
+            Change::Patch { record_id, patch } => batch.patches.push(BatchPatch {
+                id: record_id,
+                patch,
+            }),
+            Change::Create(request) => batch.posts.push(request),
+            Change::Delete { record_id } => batch.deletes.push(BatchDelete { id: record_id }),
+        }
+    }
+    if batch.is_empty() {
+        log::info!("(\"{id}\"): No changes to apply");
+        return;
+    }
+
+    log::info!(
+        "(\"{id}\"): Applying {} changes in one batch ({} patches, {} creations, {} deletions)",
+        batch.len(),
+        batch.patches.len(),
+        batch.posts.len(),
+        batch.deletes.len()
+    );
+    match client.batch(zone.id(), &batch).await {
+        Ok(response) => {
+            log::info!("(\"{id}\"): Successfully applied batch");
+            result.patched += response.patches.len() as u16;
+            result.created += response.posts.len() as u16;
+            result.deleted += response.deletes.len() as u16;
+            for record in response.patches.iter().chain(&response.posts) {
+                result
+                    .records
+                    .insert(record.id.to_string(), RecordState::from(record));
+            }
+        }
+        Err(e) => {
+            log::error!(
+                "(\"{id}\"): Error while applying batch, none of its {} changes were made: {e}",
+                batch.len()
             );
-            continue;
+            result.errors.push(e);
         }
+    }
+}
 
-        let record_arc: Arc<(dyn Record + Send + Sync)> = Arc::new(record);
-        let client_arc_2 = client_arc.clone();
-        let zone_arc_2 = zone_arc.clone();
+/// Adds the records matched by each search rule of the zone to the listing
+pub async fn list_zone(
+    zone: &Zone,
+    client: &CloudflareClient,
+    listing: &mut RecordListing,
+) -> Result<(), Error> {
+    let results = join_all(
+        zone.search
+            .iter()
+            .map(|rule| client.list_records(zone.id(), rule)),
+    )
+    .await;
+    for (i, result) in results.into_iter().enumerate() {
+        for record in result? {
+            listing.add(zone.display_name(), i, &record);
+        }
+    }
+    Ok(())
+}
 
-        futures.push(tokio::spawn(async move {
-            let id = &zone_arc_2.identifier;
-            let record_name = record_arc.get_name();
+/// Adds the changes needed to bring the records of a zone up to date to the plan, without patching anything
+pub async fn plan_zone(
+    zone: &Zone,
+    client: &CloudflareClient,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    failed: FailedFamilies,
+    plan: &mut Plan,
+) -> Result<(), Error> {
+    let records = list_records(zone, client).await?;
+    for matched in records.values() {
+        let settings = &matched.settings;
+        let record_addresses =
+            record_addresses(zone, settings.ipv6_rewrite.as_ref(), addresses, failed);
+        let action = plan_record(
+            &matched.record,
+            record_addresses,
+            zone.missing_family,
+            failed,
+            settings,
+        );
+        plan.add(zone.display_name(), &matched.record, action);
+    }
+    for ensure in &zone.ensure {
+        let ensure_addresses =
+            record_addresses(zone, ensure.ipv6_rewrite().as_ref(), addresses, failed);
+        let found = records.values().map(|m| &m.record);
+        if let Some(action) = plan_ensure(ensure, found, ensure_addresses) {
+            plan.add_missing(zone.display_name(), ensure, action);
+        }
+    }
+    Ok(())
+}
 
-            match patch_ip_record_address(
-                zone_arc_2.clone(),
-                record_arc.clone(),
-                client_arc_2,
-                addresses,
-            )
+/// Checks the credentials of all zones, so that wrong credentials are reported at startup.
+/// Credentials shared by several zones are only checked once.
+pub async fn verify_credentials(zones: &[Zone], clients: &Clients) -> Result<(), Error> {
+    let mut verified: Vec<Arc<CloudflareClient>> = Vec::with_capacity(clients.len());
+    for zone in zones {
+        let client = clients.for_zone(zone);
+        if verified.iter().any(|c| Arc::ptr_eq(c, &client)) {
+            continue;
+        }
+        let id = zone.display_name();
+        log::info!("(\"{id}\"): Verifying credentials");
+        client
+            .verify()
             .await
-            {
-                Ok(response) => {
-                    if response.success {
-                        log::info!("(\"{id}\"): ({record_name}): Successfully patched record");
-                    } else {
-                        log::error!(
-                            "(\"{id}\"): ({record_name}): Patch unsuccessful: {:#?}",
-                            response.messages
-                        );
-                    };
-                    response.success
-                }
-                Err(e) => {
-                    log::error!("(\"{id}\"): ({record_name}): {}", e);
-                    false
-                }
+            .inspect_err(|e| log::error!("(\"{id}\"): Credentials were not accepted: {e}"))?;
+        verified.push(client);
+    }
+    Ok(())
+}
+
+/// Checks the credentials of all zones before a run, unless the state shows that the run would not
+/// change any zone. A run without changes then does not contact Cloudflare at all.
+pub async fn verify_credentials_for_run(
+    zones: &[Zone],
+    clients: &Clients,
+    state: &State,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    failed: FailedFamilies,
+) -> Result<(), Error> {
+    if state.is_up_to_date(zones, addresses, failed) {
+        log::info!("All zones are up to date, not verifying credentials");
+        return Ok(());
+    }
+    verify_credentials(zones, clients).await
+}
+
+/// Fills in the ids of the zones configured by name. Ids are looked up once and then remembered in the state.
+pub async fn resolve_zones(
+    zones: &mut [Zone],
+    clients: &Clients,
+    state: &mut State,
+) -> Result<(), Error> {
+    for zone in zones.iter_mut().filter(|z| z.identifier.is_none()) {
+        let name = zone
+            .name
+            .clone()
+            .expect("Zones without an identifier should have a name");
+        let id = match state.zone_ids.get(&name) {
+            Some(id) => id.clone(),
+            None => {
+                let id = find_zone_id(&clients.for_zone(zone), &name).await?;
+                state.zone_ids.insert(name.clone(), id.clone());
+                id
             }
-        }));
+        };
+        log::debug!("(\"{name}\"): Zone id is {id}");
+        zone.identifier = Some(MaxLenString(id));
+    }
+    Ok(())
+}
+
+async fn find_zone_id(client: &CloudflareClient, name: &str) -> Result<String, Error> {
+    log::info!("(\"{name}\"): Looking up zone id");
+    let found = client.find_zone(name).await?.ok_or_else(|| {
+        Error::Config(format!(
+            "Zone \"{name}\" not found, check the name and that the token may read the zone"
+        ))
+    })?;
+    Ok(found.id.0)
+}
+
+/// Looks up the id of a zone configured by name again after a request with its remembered id
+/// failed with [`Error::NotFound`], e.g. as the zone was deleted and created again.
+/// Returns whether the zone now has a different id, in which case the request should be retried once.
+pub async fn refresh_zone_id(
+    zone: &mut Zone,
+    client: &CloudflareClient,
+    state: &mut State,
+) -> bool {
+    let Some(name) = zone.name.clone() else {
+        return false;
+    };
+    let old_id = zone.id().to_owned();
+    if state.zone_ids.remove(&name).is_none() {
+        // The id was not remembered, so it was just looked up
+        return false;
     }
-    let mut success_count = 0;
-    for r in join_all(futures).await {
-        if r? {
-            success_count += 1;
+    state.zones.remove(&old_id);
+    match find_zone_id(client, &name).await {
+        Ok(id) if id != old_id => {
+            log::info!("(\"{name}\"): Zone was not found with id {old_id}, it now has id {id}");
+            state.zone_ids.insert(name, id.clone());
+            zone.identifier = Some(MaxLenString(id));
+            true
+        }
+        Ok(id) => {
+            state.zone_ids.insert(name, id);
+            false
+        }
+        Err(e) => {
+            log::error!("(\"{name}\"): Could not look up the zone id again: {e}");
+            false
         }
     }
+}
 
-    Ok(success_count)
+/// Patches all zones one after another, skipping zones that the state marks as up to date
+/// with the given addresses and their current config. Zones are only marked up to date if all of their records could be patched.
+/// The records of families whose detection failed are left unchanged, so the state keeps their last address.
+///
+/// A failing zone does not stop the other zones from being patched; the first error is returned
+/// once all zones have been tried.
+pub async fn patch_zones(
+    zones: &mut [Zone],
+    clients: &Clients,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    failed: FailedFamilies,
+    state: &mut State,
+    summary: &mut RunSummary,
+) -> Result<(), Error> {
+    let mut first_error = None;
+    state.update_addresses(state.known_addresses(addresses, failed));
+    for zone in zones.iter_mut() {
+        let id = zone.display_name().to_owned();
+        let mut zone_summary = ZoneSummary {
+            zone: id.clone(),
+            status: ZoneStatus::Updated,
+            patched: 0,
+            created: 0,
+            deleted: 0,
+            failed: 0,
+        };
+        if state
+            .zones
+            .get(zone.id())
+            .is_some_and(|z| z.is_up_to_date(zone))
+        {
+            log::info!("(\"{id}\"): Addresses and config unchanged since last update, skipping");
+            zone_summary.status = ZoneStatus::UpToDate;
+            summary.zones.push(zone_summary);
+            continue;
+        }
+        let client = clients.for_zone(zone);
+        let mut result = patch_zone(zone, client.clone(), addresses, failed).await;
+        if matches!(result, Err(Error::NotFound { .. }))
+            && refresh_zone_id(zone, &client, state).await
+        {
+            result = patch_zone(zone, client, addresses, failed).await;
+        }
+        match result {
+            Ok(r) => {
+                zone_summary.patched = r.patched;
+                zone_summary.created = r.created;
+                zone_summary.deleted = r.deleted;
+                zone_summary.failed = r.errors.len();
+                log::info!("(\"{id}\"): Patched {} records", r.patched);
+                if r.created > 0 {
+                    log::info!("(\"{id}\"): Created {} records", r.created);
+                }
+                if r.deleted > 0 {
+                    log::info!("(\"{id}\"): Deleted {} records", r.deleted);
+                }
+                if r.errors.is_empty() {
+                    let zone_state = ZoneState {
+                        records: r.records,
+                        config_hash: ZoneState::config_hash(zone),
+                    };
+                    state.zones.insert(zone.id().to_owned(), zone_state);
+                } else {
+                    log::warn!("(\"{id}\"): Failed to change {} records", r.errors.len());
+                    zone_summary.status = ZoneStatus::Failed;
+                    first_error = first_error.or(r.errors.into_iter().next());
+                }
+            }
+            Err(e) => {
+                log::error!("(\"{id}\"): Error while patching records: {e}");
+                zone_summary.status = ZoneStatus::Failed;
+                first_error = first_error.or(Some(e));
+            }
+        };
+        summary.zones.push(zone_summary);
+    }
+    first_error.map_or(Ok(()), Err)
 }
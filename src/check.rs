@@ -0,0 +1,440 @@
+//! Offline checks of the config file, reporting the line and column of every problem found
+
+use std::{
+    fmt::{Display, Formatter},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::Config,
+    error::Error,
+    loader::{merge, ConfigFormat, ConfigSource, Overrides},
+};
+use serde::{
+    de::{DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The config can not be used
+    Error,
+    /// The config can be used, but probably does not do what was intended
+    Warning,
+}
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        }
+        .fmt(f)
+    }
+}
+
+/// Part of the path of a value in the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(&'static str),
+    Index(usize),
+}
+
+/// A problem of a parsed config, see [`Config::problems`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Path of the value causing the problem, e.g. `zones[0].search[1].template`.
+    /// Empty if the problem concerns the whole file.
+    pub path: Vec<PathSegment>,
+    pub message: String,
+}
+impl Problem {
+    pub fn error(path: Vec<PathSegment>, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            path,
+            message,
+        }
+    }
+
+    pub fn warning(path: Vec<PathSegment>, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            path,
+            message,
+        }
+    }
+}
+
+/// A problem with its position in the config file
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// File the problem was found in, if the config is read from files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Zone the problem was found in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// Line of the problem, starting at 1. Missing if the problem concerns the whole file.
+    pub line: Option<usize>,
+    /// Column of the problem in characters, starting at 1
+    pub column: Option<usize>,
+    pub message: String,
+}
+impl Diagnostic {
+    fn new(text: &str, severity: Severity, span: Option<Range<usize>>, message: String) -> Self {
+        let (line, column) = match span {
+            Some(span) => {
+                let (line, column) = position(text, span.start);
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        Self::at(line.zip(column), message).with_severity(severity)
+    }
+
+    /// Reports a syntax error at a line and column
+    pub(crate) fn at(position: Option<(usize, usize)>, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            file: None,
+            zone: None,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            message,
+        }
+    }
+
+    fn with_severity(self, severity: Severity) -> Self {
+        Self { severity, ..self }
+    }
+
+    /// Reports a config file that is not valid TOML
+    pub fn from_parse_error(text: &str, e: &toml::de::Error) -> Self {
+        Self::new(text, Severity::Error, e.span(), e.message().to_owned())
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{line}:{column}: ")?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Line and column of a byte offset, both starting at 1
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Whether the item is written in the file itself. Tables that are only implied by the headers of
+/// their subtables, e.g. `accounts` of `[accounts.home]`, or by dotted keys have no position.
+fn is_written(item: &toml_edit::Item) -> bool {
+    match item {
+        toml_edit::Item::Table(table) => !table.is_implicit() && !table.is_dotted(),
+        toml_edit::Item::Value(toml_edit::Value::InlineTable(table)) => !table.is_dotted(),
+        _ => true,
+    }
+}
+
+/// The part of the path leading to the deepest value that is written in the document
+fn written_path<'p, 'a>(document: &toml_edit::Document, path: &'p [Step<'a>]) -> &'p [Step<'a>] {
+    let mut current = document.as_item();
+    let mut written = 0;
+    for (depth, step) in path.iter().enumerate() {
+        let child = match *step {
+            Step::Key(key) => current.get(key),
+            Step::Index(i) => current.get(i),
+        };
+        let Some(child) = child else {
+            break;
+        };
+        if is_written(child) {
+            written = depth + 1;
+        }
+        current = child;
+    }
+    &path[..written]
+}
+
+/// Deserializes the value at the path as [`toml::Spanned`] to get its position, ignoring all other values
+struct SpanAt<'p, 'a>(&'p [Step<'a>]);
+
+impl<'de> DeserializeSeed<'de> for SpanAt<'_, '_> {
+    type Value = Option<Range<usize>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        match self.0 {
+            [] => Ok(Some(
+                toml::Spanned::<IgnoredAny>::deserialize(deserializer)?.span(),
+            )),
+            _ => deserializer.deserialize_any(self),
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for SpanAt<'_, '_> {
+    type Value = Option<Range<usize>>;
+
+    fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str("a table or array containing the value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut span = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match self.0 {
+                [Step::Key(k), rest @ ..] if *k == key => {
+                    span = map.next_value_seed(SpanAt(rest))?
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(span)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut span = None;
+        for i in 0.. {
+            let item = match self.0 {
+                [Step::Index(index), rest @ ..] if *index == i => {
+                    seq.next_element_seed(SpanAt(rest))?.map(|s| span = s)
+                }
+                _ => seq.next_element::<IgnoredAny>()?.map(|_| ()),
+            };
+            if item.is_none() {
+                break;
+            }
+        }
+        Ok(span)
+    }
+}
+
+/// Span of the value at the path, or of the closest parent that is written in the file.
+/// Problems of the whole file have no span.
+fn locate(
+    text: &str,
+    document: &toml_edit::Document,
+    path: &[Step],
+) -> Result<Option<Range<usize>>, toml::de::Error> {
+    match written_path(document, path) {
+        [] => Ok(None),
+        path => SpanAt(path).deserialize(toml::Deserializer::new(text)),
+    }
+}
+
+/// Part of a path to a value, borrowing the keys unlike [`PathSegment`]
+#[derive(Debug, Clone, Copy)]
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+impl From<&PathSegment> for Step<'_> {
+    fn from(segment: &PathSegment) -> Self {
+        match *segment {
+            PathSegment::Key(key) => Self::Key(key),
+            PathSegment::Index(i) => Self::Index(i),
+        }
+    }
+}
+
+/// Path of the value a deserialization error occurred at, up to the first part that is not known
+fn error_path(path: &serde_path_to_error::Path) -> Vec<Step<'_>> {
+    use serde_path_to_error::Segment;
+    path.iter()
+        .map_while(|segment| match segment {
+            Segment::Seq { index } => Some(Step::Index(*index)),
+            Segment::Map { key } => Some(Step::Key(key)),
+            Segment::Enum { variant } => Some(Step::Key(variant)),
+            Segment::Unknown => None,
+        })
+        .collect()
+}
+
+/// One of the files the config is merged from
+struct Fragment<'a> {
+    file: Option<&'a Path>,
+    text: &'a str,
+    /// Document to locate values in, which is only known for TOML files
+    document: Option<toml_edit::Document>,
+    /// Top-level keys set by the file
+    keys: Vec<String>,
+    /// Number of zones of the file, the zones of the next file follow them
+    zones: usize,
+}
+impl Fragment<'_> {
+    fn diagnostic(&self, severity: Severity, path: &[Step], message: String) -> Diagnostic {
+        let located = match &self.document {
+            Some(document) => locate(self.text, document, path),
+            None => Ok(None),
+        };
+        let (span, message) = match located {
+            Ok(span) => (span, message),
+            Err(e) => (
+                None,
+                format!("{message} (could not locate it: {})", e.message()),
+            ),
+        };
+        Diagnostic {
+            file: self.file.map(Path::to_owned),
+            ..Diagnostic::new(self.text, severity, span, message)
+        }
+    }
+}
+
+/// Reports a problem in the file that sets the value at the path. Zones are found in the file
+/// that defines them, other values in the last file that sets them.
+fn diagnose(
+    fragments: &[Fragment],
+    severity: Severity,
+    path: &[Step],
+    message: String,
+) -> Diagnostic {
+    match path {
+        [Step::Key("zones"), Step::Index(i), rest @ ..] => {
+            let mut index = *i;
+            for fragment in fragments {
+                if index < fragment.zones {
+                    let mut local = vec![Step::Key("zones"), Step::Index(index)];
+                    local.extend_from_slice(rest);
+                    return fragment.diagnostic(severity, &local, message);
+                }
+                index -= fragment.zones;
+            }
+        }
+        [Step::Key(key), ..] => {
+            if let Some(fragment) = fragments
+                .iter()
+                .rev()
+                .find(|f| f.keys.iter().any(|k| k == key))
+            {
+                return fragment.diagnostic(severity, path, message);
+            }
+        }
+        _ => {}
+    }
+    match fragments {
+        [fragment] => fragment.diagnostic(severity, &[], message),
+        _ => Diagnostic::at(None, message).with_severity(severity),
+    }
+}
+
+/// Parses, merges and validates the files of the config, given with their path and text,
+/// after applying the overrides of the environment and the command line
+fn check_files(
+    files: &[(Option<&Path>, &str)],
+    overrides: &[Overrides],
+    zones: &[String],
+) -> Vec<Diagnostic> {
+    let mut value = serde_json::Value::Object(Default::default());
+    let mut fragments = Vec::with_capacity(files.len());
+    for &(file, text) in files {
+        let format = file.map_or(ConfigFormat::Toml, ConfigFormat::of);
+        let fragment = match format.parse(text) {
+            Ok(fragment) => fragment,
+            Err(d) => {
+                return vec![Diagnostic {
+                    file: file.map(Path::to_owned),
+                    ..d
+                }]
+            }
+        };
+        let document = match format {
+            ConfigFormat::Toml => match text.parse::<toml_edit::Document>() {
+                Ok(document) => Some(document),
+                Err(e) => {
+                    return vec![Diagnostic {
+                        file: file.map(Path::to_owned),
+                        ..Diagnostic::new(text, Severity::Error, e.span(), e.message().to_owned())
+                    }]
+                }
+            },
+            ConfigFormat::Yaml | ConfigFormat::Json => None,
+        };
+        fragments.push(Fragment {
+            file,
+            text,
+            document,
+            keys: fragment
+                .as_object()
+                .map(|table| table.keys().cloned().collect())
+                .unwrap_or_default(),
+            zones: fragment
+                .get("zones")
+                .and_then(|z| z.as_array())
+                .map_or(0, Vec::len),
+        });
+        merge(&mut value, fragment);
+    }
+    for o in overrides {
+        o.apply(&mut value);
+    }
+    let mut conf: Config = match serde_path_to_error::deserialize(value) {
+        Ok(conf) => conf,
+        Err(e) => {
+            let message = e.inner().to_string();
+            return vec![diagnose(
+                &fragments,
+                Severity::Error,
+                &error_path(e.path()),
+                message,
+            )];
+        }
+    };
+    conf.apply_defaults();
+    let mut diagnostics: Vec<_> = zones
+        .iter()
+        .filter(|n| !conf.zones.iter().any(|z| z.is_named(n)))
+        .map(|n| Diagnostic::at(None, format!("No zone \"{n}\" is configured")))
+        .collect();
+    for problem in conf.problems() {
+        let zone = match problem.path[..] {
+            [PathSegment::Key("zones"), PathSegment::Index(i), ..] => Some(&conf.zones[i]),
+            _ => None,
+        };
+        if zone.is_some_and(|z| !zones.is_empty() && !zones.iter().any(|n| z.is_named(n))) {
+            continue;
+        }
+        let path: Vec<Step> = problem.path.iter().map(Step::from).collect();
+        let mut diagnostic = diagnose(&fragments, problem.severity, &path, problem.message);
+        diagnostic.zone = zone.map(|z| z.display_name().to_owned());
+        diagnostics.push(diagnostic);
+    }
+    diagnostics.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+    diagnostics
+}
+
+/// Parses and validates a TOML config without reading secrets or contacting any service.
+/// If zones are given by name or identifier, only the problems of these zones and of the whole file are reported.
+pub fn check_str(text: &str, zones: &[String]) -> Vec<Diagnostic> {
+    check_files(&[(None, text)], &[], zones)
+}
+
+/// Reads the config files and checks them like [`check_str`], naming the file of every problem.
+/// The overrides are applied before checking, as when the config is read to be used.
+pub async fn check_config(
+    source: &ConfigSource,
+    overrides: &[Overrides],
+    zones: &[String],
+) -> Result<Vec<Diagnostic>, Error> {
+    let files = match source.overrides_only(overrides).await {
+        true => Vec::new(),
+        false => source.read_files().await?,
+    };
+    let files: Vec<_> = files
+        .iter()
+        .map(|(path, text)| (Some(path.as_path()), text.as_str()))
+        .collect();
+    Ok(check_files(&files, overrides, zones))
+}
@@ -0,0 +1,456 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    Method, Request, RequestBuilder,
+};
+use serde::de::DeserializeOwned;
+use tokio::{
+    sync::Semaphore,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    config::{
+        Authorization, Config, RetryConfig, SearchRule, Zone, DEFAULT_MAX_CONCURRENT_REQUESTS,
+    },
+    error::Error,
+    records::{
+        ApiResponse, BatchRequest, BatchResponse, ListResponse, RecordPatch, RecordRequest,
+        RecordResponse, TokenStatus, ZoneResponse,
+    },
+};
+
+fn authenticate_request(
+    mut req: RequestBuilder,
+    auth: &Authorization,
+) -> Result<RequestBuilder, Error> {
+    for (name, value) in auth.headers()? {
+        req = match HeaderValue::from_str(&value) {
+            Ok(mut value) => {
+                // Keeps the credentials out of debug output
+                value.set_sensitive(true);
+                req.header(name, value)
+            }
+            // Fails when the request is built
+            Err(_) => req.header(name, value),
+        };
+    }
+    Ok(req)
+}
+
+/// Reads the time to wait from the `Retry-After` header of a response, given either as
+/// number of seconds or as HTTP date (RFC 9110)
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("Retry-After")?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date in the past allows retrying right away
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Delays between retries never grow beyond this, unless the configured backoff is longer
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Exponential delay before the given retry (starting at 1), randomized between half and the full delay
+fn backoff(retry: &RetryConfig, n: u32) -> Duration {
+    let delay = retry
+        .backoff()
+        .saturating_mul(2u32.saturating_pow(n - 1))
+        .min(MAX_BACKOFF.max(retry.backoff()));
+    let delay = delay / 2 + delay.mul_f64(fastrand::f64() / 2.0);
+    Duration::from_millis(delay.as_millis() as u64)
+}
+
+/// Whether a failed request may be sent again.
+/// Requests that are not idempotent are only retried if they can not have been processed.
+fn is_retryable(e: &Error, idempotent: bool) -> bool {
+    match e {
+        Error::RateLimited { .. } => true,
+        Error::Transport(e) => idempotent || e.is_connect(),
+        Error::ApiError { code, .. } => idempotent && code.is_server_error(),
+        _ => false,
+    }
+}
+
+/// Http client, retry policy and request limit shared by the clients of all zones
+#[derive(Clone)]
+pub struct ClientContext {
+    pub http: reqwest::Client,
+    retry: RetryConfig,
+    limit: Arc<Semaphore>,
+}
+
+impl ClientContext {
+    pub fn new(http: reqwest::Client, retry: RetryConfig, max_concurrent_requests: usize) -> Self {
+        Self {
+            http,
+            retry,
+            limit: Arc::new(Semaphore::new(max_concurrent_requests)),
+        }
+    }
+
+    pub fn from_config(conf: &Config) -> Self {
+        Self::new(
+            reqwest::Client::new(),
+            conf.retry,
+            conf.max_concurrent_requests(),
+        )
+    }
+}
+
+impl Default for ClientContext {
+    fn default() -> Self {
+        Self::new(
+            reqwest::Client::new(),
+            RetryConfig::default(),
+            DEFAULT_MAX_CONCURRENT_REQUESTS,
+        )
+    }
+}
+
+/// Client for the DNS record endpoints of the Cloudflare API
+#[derive(Clone)]
+pub struct CloudflareClient {
+    context: ClientContext,
+    base_url: String,
+    auth: Authorization,
+    account_id: Option<String>,
+}
+
+impl CloudflareClient {
+    pub fn new(context: ClientContext, base_url: &str, auth: Authorization) -> Self {
+        Self {
+            context,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            auth,
+            account_id: None,
+        }
+    }
+
+    /// Only looks up zones in the given account
+    pub fn with_account_id(mut self, account_id: Option<String>) -> Self {
+        self.account_id = account_id;
+        self
+    }
+
+    /// Creates a client using the api url, authorization and account of the zone
+    pub fn for_zone(context: ClientContext, zone: &Zone) -> Self {
+        Self::new(context, zone.api_url(), zone.auth().clone())
+            .with_account_id(zone.account_id.clone())
+    }
+
+    /// Whether the client uses the api url, authorization and account of the zone
+    pub fn serves(&self, zone: &Zone) -> bool {
+        self.base_url == zone.api_url().trim_end_matches('/')
+            && &self.auth == zone.auth()
+            && self.account_id == zone.account_id
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Builds an authenticated request, failing if a secret of the credentials has not been read
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, Error> {
+        let req = self
+            .context
+            .http
+            .request(method, format!("{}{}", self.base_url, path))
+            .header("Content-Type", "application/json");
+        authenticate_request(req, &self.auth)
+    }
+
+    /// Sends the request, retrying it with a jittered exponential backoff if it failed transiently.
+    /// Rate limited requests are retried after the delay given by the API.
+    async fn send<T: DeserializeOwned>(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<ApiResponse<T>, Error> {
+        let req = req.build()?;
+        let idempotent = req.method() != Method::POST;
+        let retry = &self.context.retry;
+        let deadline = Instant::now() + retry.deadline();
+
+        let mut attempt = 1;
+        loop {
+            let attempt_req = req
+                .try_clone()
+                .expect("Api requests should not have a streaming body");
+            let e = match self.send_once(attempt_req).await {
+                Ok(r) => return Ok(r),
+                Err(e) => e,
+            };
+            if attempt >= retry.max_attempts() || !is_retryable(&e, idempotent) {
+                return Err(e);
+            }
+            let delay = match e {
+                Error::RateLimited {
+                    retry_after: Some(d),
+                } => d,
+                _ => backoff(retry, attempt),
+            };
+            if Instant::now() + delay > deadline {
+                log::debug!(
+                    "Not retrying {} {}, deadline would be exceeded",
+                    req.method(),
+                    req.url()
+                );
+                return Err(e);
+            }
+            log::warn!(
+                "{} {}: {e}, retrying in {}",
+                req.method(),
+                req.url().path(),
+                humantime::format_duration(delay)
+            );
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends the request once, waiting for a free slot of the request limit
+    async fn send_once<T: DeserializeOwned>(&self, req: Request) -> Result<ApiResponse<T>, Error> {
+        let _permit = self
+            .context
+            .limit
+            .acquire()
+            .await
+            .expect("Request limit should never be closed");
+        let response = self.context.http.execute(req).await?;
+
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let text = response.text().await?;
+
+        if !status.is_success() {
+            let messages = match serde_json::from_str::<ApiResponse<serde_json::Value>>(&text) {
+                Ok(r) => r.errors,
+                Err(_) => {
+                    log::debug!("Response of code {status} is not an api response: {text}");
+                    Vec::new()
+                }
+            };
+            return Err(Error::from_response(status, messages, retry_after));
+        }
+        let response: ApiResponse<T> = serde_json::from_str(&text)?;
+        if !response.success {
+            return Err(Error::from_response(status, response.errors, retry_after));
+        }
+        Ok(response)
+    }
+
+    async fn send_for_result<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T, Error> {
+        self.send(req).await?.result.ok_or(Error::MissingResult)
+    }
+
+    /// Checks that the API accepts the credentials. API tokens also have to be active.
+    ///
+    /// Tokens of clients with an account id are verified as tokens owned by the account first,
+    /// then as tokens of a user. Origin CA keys can not be verified and are assumed to be valid.
+    pub async fn verify(&self) -> Result<(), Error> {
+        match &self.auth {
+            Authorization::BearerAuth(_) => match &self.account_id {
+                Some(account_id) => {
+                    let path = format!("/accounts/{account_id}/tokens/verify");
+                    match self.verify_token(&path).await {
+                        Err(Error::Auth { .. }) => {
+                            log::debug!(
+                                "Token is not owned by the account, verifying it as user token"
+                            );
+                            self.verify_token("/user/tokens/verify").await?
+                        }
+                        r => r?,
+                    }
+                }
+                None => self.verify_token("/user/tokens/verify").await?,
+            },
+            Authorization::ApiKey(_) => {
+                let req = self.request(Method::GET, "/user")?;
+                self.send_for_result::<serde_json::Value>(req).await?;
+            }
+            Authorization::OriginCaKey(_) => {
+                log::debug!("Origin CA keys can not be verified, skipping");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the token is active with the verify endpoint at the path
+    async fn verify_token(&self, path: &str) -> Result<(), Error> {
+        let req = self.request(Method::GET, path)?;
+        let response: ApiResponse<TokenStatus> = self.send(req).await?;
+        let token = response.result.ok_or(Error::MissingResult)?;
+        if token.status != "active" {
+            log::error!("API token is {}", token.status);
+            return Err(Error::Auth {
+                messages: response.messages,
+            });
+        }
+        Ok(())
+    }
+
+    /// Looks up a zone by its name, e.g. `example.com`
+    pub async fn find_zone(&self, name: &str) -> Result<Option<ZoneResponse>, Error> {
+        let mut req = self
+            .request(Method::GET, "/zones")?
+            .query(&[("name", name)]);
+        if let Some(account_id) = &self.account_id {
+            req = req.query(&[("account.id", account_id)]);
+        }
+        let zones: Vec<ZoneResponse> = self.send_for_result(req).await?;
+        Ok(zones
+            .into_iter()
+            .find(|z| z.name.eq_ignore_ascii_case(name)))
+    }
+
+    /// Lists a single page of the records matching the rule. If `page` is `None`,
+    /// the page set in the rule (or the first page) is returned.
+    pub async fn list_records_page(
+        &self,
+        zone_id: &str,
+        rule: &SearchRule,
+        page: Option<u32>,
+    ) -> Result<ListResponse, Error> {
+        let mut url_params = serde_url_params::to_string(&rule.query())?;
+        if let Some(page) = page {
+            url_params = format!("{url_params}&page={page}");
+        }
+        let req = self.request(
+            Method::GET,
+            &format!("/zones/{zone_id}/dns_records?{url_params}"),
+        )?;
+        self.send(req).await
+    }
+
+    /// Lists all records matching the rule, following all result pages unless the rule pins a page
+    pub async fn list_records(
+        &self,
+        zone_id: &str,
+        rule: &SearchRule,
+    ) -> Result<Vec<RecordResponse>, Error> {
+        if rule.page.is_some() {
+            let response = self.list_records_page(zone_id, rule, None).await?;
+            return Ok(response.result.unwrap_or_default());
+        }
+
+        let mut records = Vec::new();
+        let mut page: u32 = 1;
+        loop {
+            let response = self.list_records_page(zone_id, rule, Some(page)).await?;
+            let result = response.result.unwrap_or_default();
+            let last_page = result.is_empty()
+                || match &response.result_info {
+                    Some(info) => page >= info.total_pages(),
+                    None => true,
+                };
+            records.extend(result);
+
+            if last_page {
+                return Ok(records);
+            }
+            page += 1;
+        }
+    }
+
+    pub async fn get_record(
+        &self,
+        zone_id: &str,
+        record_id: &str,
+    ) -> Result<RecordResponse, Error> {
+        let req = self.request(
+            Method::GET,
+            &format!("/zones/{zone_id}/dns_records/{record_id}"),
+        )?;
+        self.send_for_result(req).await
+    }
+
+    pub async fn create_record(
+        &self,
+        zone_id: &str,
+        record: &RecordRequest,
+    ) -> Result<RecordResponse, Error> {
+        let req = self
+            .request(Method::POST, &format!("/zones/{zone_id}/dns_records"))?
+            .json(record);
+        self.send_for_result(req).await
+    }
+
+    pub async fn patch_record(
+        &self,
+        zone_id: &str,
+        record_id: &str,
+        patch: &RecordPatch,
+    ) -> Result<RecordResponse, Error> {
+        let req = self
+            .request(
+                Method::PATCH,
+                &format!("/zones/{zone_id}/dns_records/{record_id}"),
+            )?
+            .json(patch);
+        self.send_for_result(req).await
+    }
+
+    /// Applies all changes of the batch at once, the API rolls back all of them if one fails
+    pub async fn batch(&self, zone_id: &str, batch: &BatchRequest) -> Result<BatchResponse, Error> {
+        let req = self
+            .request(Method::POST, &format!("/zones/{zone_id}/dns_records/batch"))?
+            .json(batch);
+        self.send_for_result(req).await
+    }
+
+    pub async fn delete_record(&self, zone_id: &str, record_id: &str) -> Result<(), Error> {
+        let req = self.request(
+            Method::DELETE,
+            &format!("/zones/{zone_id}/dns_records/{record_id}"),
+        )?;
+        self.send::<serde_json::Value>(req).await?;
+        Ok(())
+    }
+}
+
+/// The clients of all zones. Zones with the same api url, credentials and account share one client.
+pub struct Clients {
+    context: ClientContext,
+    clients: Vec<Arc<CloudflareClient>>,
+}
+
+impl Clients {
+    pub fn new(context: ClientContext, zones: &[Zone]) -> Self {
+        let mut clients: Vec<Arc<CloudflareClient>> = Vec::new();
+        for zone in zones {
+            if !clients.iter().any(|c| c.serves(zone)) {
+                clients.push(Arc::new(CloudflareClient::for_zone(context.clone(), zone)));
+            }
+        }
+        Self { context, clients }
+    }
+
+    pub fn context(&self) -> &ClientContext {
+        &self.context
+    }
+
+    /// The client shared by the zone, or a new one for zones not known when the clients were created
+    pub fn for_zone(&self, zone: &Zone) -> Arc<CloudflareClient> {
+        match self.clients.iter().find(|c| c.serves(zone)) {
+            Some(client) => client.clone(),
+            None => Arc::new(CloudflareClient::for_zone(self.context.clone(), zone)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
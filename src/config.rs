@@ -1,7 +1,41 @@
-use crate::misc_serialization::*;
+use crate::{
+    check::{
+        PathSegment::{self, Index, Key},
+        Problem, Severity,
+    },
+    error::Error,
+    loader::{config_from_value, ConfigSource, Overrides},
+    misc_serialization::*,
+    secret::Secret,
+    template,
+};
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 use serde_with::with_prefix;
+use tokio::{fs::File, io::AsyncReadExt};
+
+/// Cloudflare API endpoint used if none is configured
+pub const DEFAULT_API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Interval used in daemon mode if none is configured
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(60);
+
+/// Length of the prefix kept from the detected address when only an IPv6 suffix is configured
+pub const DEFAULT_IPV6_PREFIX_LENGTH: u8 = 64;
+
+/// Requests sent to the Cloudflare API at the same time if not configured otherwise
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct StringMatch {
@@ -60,17 +94,67 @@ pub enum RecordType {
     URI,
 }
 
+/// Builds IPv6 addresses from the prefix of the detected address and a fixed interface identifier,
+/// e.g. for hosts behind a router that receives a delegated prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Rewrite {
+    pub suffix: Ipv6Addr,
+    pub prefix_length: u8,
+}
+impl Ipv6Rewrite {
+    pub fn apply(&self, addr: Ipv6Addr) -> Ipv6Addr {
+        let mask = u128::MAX
+            .checked_shl(128 - self.prefix_length as u32)
+            .unwrap_or(0);
+        Ipv6Addr::from(u128::from(addr) & mask | u128::from(self.suffix) & !mask)
+    }
+
+    /// The addresses with the IPv6 address rewritten
+    pub fn apply_to(
+        rewrite: Option<&Self>,
+        addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    ) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        match rewrite {
+            Some(r) => (addresses.0, addresses.1.map(|a| r.apply(a))),
+            None => addresses,
+        }
+    }
+}
+
+/// Whether a record name is the name of the zone or a subdomain of it, ignoring case and a trailing dot
+fn is_in_zone(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    name == zone || name.ends_with(&format!(".{zone}"))
+}
+
+/// Creates the rewrite for a suffix and prefix length as configured in search rules and ensured records
+fn ipv6_rewrite(suffix: Option<Ipv6Addr>, prefix_length: Option<u8>) -> Option<Ipv6Rewrite> {
+    Some(Ipv6Rewrite {
+        suffix: suffix?,
+        prefix_length: prefix_length.unwrap_or(DEFAULT_IPV6_PREFIX_LENGTH),
+    })
+}
+
+/// Finds the problem of a suffix and prefix length as configured in search rules and ensured records
+fn ipv6_rewrite_problem(suffix: Option<Ipv6Addr>, prefix_length: Option<u8>) -> Option<String> {
+    match (suffix, prefix_length) {
+        (None, Some(_)) => Some("ipv6_prefix_length is set without an ipv6_suffix".to_owned()),
+        (_, Some(len)) if len > 128 => {
+            Some(format!("ipv6_prefix_length {len} is longer than 128 bits"))
+        }
+        _ => None,
+    }
+}
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct SearchRule {
     #[serde(flatten, with = "prefix_comment")]
     pub comment: Option<StringMatch>,
     pub content: Option<String>,
-    #[serde(flatten, with = "prefix_direction")]
     pub direction: Option<Direction>,
-    #[serde(flatten, with = "prefix_match")]
     pub r#match: Option<Match>,
     pub name: Option<MaxLenString<255>>,
-    #[serde(flatten, with = "prefix_order")]
     pub order: Option<Order>,
     pub page: Option<MinMaxValueU16<1, { u16::MAX }>>,
     pub per_page: Option<MinMaxValueU16<5, 50000>>,
@@ -80,49 +164,820 @@ pub struct SearchRule {
     pub tag: Option<StringMatch>,
     pub tag_match: Option<String>,
     pub r#type: Option<RecordType>,
+
+    /// Interface identifier that AAAA records matched by this rule are set to,
+    /// combined with the prefix of the detected IPv6 address
+    pub ipv6_suffix: Option<Ipv6Addr>,
+    /// Length of the prefix taken from the detected IPv6 address
+    pub ipv6_prefix_length: Option<u8>,
+
+    /// Content that matched records are set to, e.g. `v=spf1 ip4:{ipv4} -all` for TXT records.
+    /// See [`template`](crate::template) for the placeholders.
+    pub template: Option<String>,
+    /// Fields besides the content that matched records are set to
+    #[serde(default)]
+    pub set: RecordFields,
+}
+impl SearchRule {
+    /// The filters of the rule, which are sent as query parameters when listing records
+    pub fn query(&self) -> RecordQuery<'_> {
+        RecordQuery {
+            comment: &self.comment,
+            content: &self.content,
+            direction: &self.direction,
+            r#match: &self.r#match,
+            name: &self.name,
+            order: &self.order,
+            page: &self.page,
+            per_page: &self.per_page,
+            proxied: &self.proxied,
+            search: &self.search,
+            tag: &self.tag,
+            tag_match: &self.tag_match,
+            r#type: &self.r#type,
+        }
+    }
+
+    pub fn ipv6_rewrite(&self) -> Option<Ipv6Rewrite> {
+        ipv6_rewrite(self.ipv6_suffix, self.ipv6_prefix_length)
+    }
+
+    pub fn settings(&self) -> RecordSettings {
+        RecordSettings {
+            ipv6_rewrite: self.ipv6_rewrite(),
+            template: self.template.clone(),
+            fields: self.set.clone(),
+        }
+    }
+
+    /// Whether the rule narrows the records down by anything but their type and proxy status
+    pub fn filters_records(&self) -> bool {
+        self.name.is_some()
+            || self.content.is_some()
+            || self.search.is_some()
+            || self.comment.is_some()
+            || self.tag.is_some()
+    }
+}
+
+/// The filters of a [`SearchRule`], without the settings applied to the records it matches
+#[derive(Serialize, Debug)]
+pub struct RecordQuery<'a> {
+    #[serde(flatten, with = "prefix_comment")]
+    comment: &'a Option<StringMatch>,
+    content: &'a Option<String>,
+    direction: &'a Option<Direction>,
+    r#match: &'a Option<Match>,
+    name: &'a Option<MaxLenString<255>>,
+    order: &'a Option<Order>,
+    page: &'a Option<MinMaxValueU16<1, { u16::MAX }>>,
+    per_page: &'a Option<MinMaxValueU16<5, 50000>>,
+    proxied: &'a Option<bool>,
+    search: &'a Option<String>,
+    #[serde(flatten, with = "prefix_tag")]
+    tag: &'a Option<StringMatch>,
+    tag_match: &'a Option<String>,
+    r#type: &'a Option<RecordType>,
+}
+
+/// Sets `field` to `other` if it is not set yet, returning `false` if both are set differently
+fn merge_field<T: PartialEq>(field: &mut Option<T>, other: Option<T>) -> bool {
+    match (&field, other) {
+        (None, other) => {
+            *field = other;
+            true
+        }
+        (Some(a), Some(b)) => *a == b,
+        (Some(_), None) => true,
+    }
+}
+
+/// Fields of a record besides its content
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct RecordFields {
+    pub ttl: Option<TTLU32>,
+    pub proxied: Option<bool>,
+    /// Template of the comment, e.g. `updated by dyndns at {timestamp}`
+    pub comment: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+impl RecordFields {
+    /// Takes the fields of `other` that are not set yet, returning `false` if both set a field differently
+    pub fn merge(&mut self, other: Self) -> bool {
+        let ttl = merge_field(&mut self.ttl, other.ttl);
+        let proxied = merge_field(&mut self.proxied, other.proxied);
+        let comment = merge_field(&mut self.comment, other.comment);
+        let tags = merge_field(&mut self.tags, other.tags);
+        ttl && proxied && comment && tags
+    }
+}
+
+/// How a rule changes the records it matches
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordSettings {
+    pub ipv6_rewrite: Option<Ipv6Rewrite>,
+    pub template: Option<String>,
+    pub fields: RecordFields,
+}
+impl RecordSettings {
+    /// Takes the settings of a later rule matching the same record where this one has none.
+    /// Returns `false` if the later rule sets something differently, which is then ignored.
+    pub fn merge(&mut self, later: Self) -> bool {
+        let rewrite = merge_field(&mut self.ipv6_rewrite, later.ipv6_rewrite);
+        let template = merge_field(&mut self.template, later.template);
+        let fields = self.fields.merge(later.fields);
+        rewrite && template && fields
+    }
 }
 
 with_prefix!(prefix_comment "comment.");
-with_prefix!(prefix_direction "direction.");
-with_prefix!(prefix_match "match.");
-with_prefix!(prefix_order "order.");
 with_prefix!(prefix_tag "tag.");
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum IpRecordType {
+    A,
+    AAAA,
+}
+impl IpRecordType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::A => "A",
+            Self::AAAA => "AAAA",
+        }
+    }
+}
+
+/// A record that has to exist in the zone, it is created if it can not be found
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnsureRecord {
+    /// Full name of the record, e.g. `host.example.com`
+    pub name: MaxLenString<255>,
+    pub r#type: IpRecordType,
+    pub ttl: Option<TTLU32>,
+    pub proxied: Option<bool>,
+    pub comment: Option<String>,
+    pub tags: Option<Vec<String>>,
+
+    /// Interface identifier of the record, see [`SearchRule::ipv6_suffix`]
+    pub ipv6_suffix: Option<Ipv6Addr>,
+    pub ipv6_prefix_length: Option<u8>,
+}
+impl EnsureRecord {
+    /// Search rule finding the existing record
+    pub fn search_rule(&self) -> SearchRule {
+        SearchRule {
+            name: Some(self.name.clone()),
+            r#type: Some(match self.r#type {
+                IpRecordType::A => RecordType::A,
+                IpRecordType::AAAA => RecordType::AAAA,
+            }),
+            ipv6_suffix: self.ipv6_suffix,
+            ipv6_prefix_length: self.ipv6_prefix_length,
+            set: self.fields(),
+            ..Default::default()
+        }
+    }
+
+    /// Fields the record is created with and kept at
+    pub fn fields(&self) -> RecordFields {
+        RecordFields {
+            ttl: self.ttl,
+            proxied: self.proxied,
+            comment: self.comment.clone(),
+            tags: self.tags.clone(),
+        }
+    }
+
+    pub fn ipv6_rewrite(&self) -> Option<Ipv6Rewrite> {
+        ipv6_rewrite(self.ipv6_suffix, self.ipv6_prefix_length)
+    }
+}
+
+/// What happens to the records of an address family that is missing, as it is not configured
+/// or the interface has no usable address
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingFamilyPolicy {
+    /// Leave the records unchanged
+    #[default]
+    keep,
+    /// Delete the records
+    delete,
+    /// Point the records at the fallback address of the family
+    replace,
+}
+
+/// Address families whose detection failed, e.g. as a service returned an error. Their records
+/// are left unchanged whatever the [`MissingFamilyPolicy`], as the address is unknown rather than missing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FailedFamilies {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Zone {
-    pub identifier: MaxLenString<32>,
-    pub auth: Authorization,
+    /// Id of the zone, looked up by `name` if not set
+    pub identifier: Option<MaxLenString<32>>,
+    /// Name of the zone, e.g. `example.com`
+    pub name: Option<String>,
+    /// Credentials of the zone, taken from the account if not set
+    pub auth: Option<Authorization>,
+    /// Name of the entry in `accounts` the zone belongs to
+    pub account: Option<String>,
+    /// Id of the account, taken from the account the zone belongs to
+    #[serde(skip)]
+    pub account_id: Option<String>,
+    /// Overrides the API endpoint configured for all zones
+    pub api_url: Option<String>,
 
+    #[serde(default)]
     pub search: Vec<SearchRule>,
+    /// Records that are created if they do not exist yet
+    #[serde(default)]
+    pub ensure: Vec<EnsureRecord>,
+
+    #[serde(default)]
+    pub missing_family: MissingFamilyPolicy,
+    /// Addresses used by the `replace` policy
+    pub ipv4_fallback: Option<Ipv4Addr>,
+    pub ipv6_fallback: Option<Ipv6Addr>,
+
+    /// Fields set on all records of the zone, unless a rule sets them differently
+    #[serde(default)]
+    pub set: RecordFields,
+
+    /// Apply all changes of the zone in a single batch request, so either all or none are made
+    #[serde(default)]
+    pub atomic: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum ApiKey {
-    Email(String),
-    Key(String),
-    UserServiceKey(String),
+/// Global API key of an account, which is only valid together with the email address of the account
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub email: Secret,
+    pub key: Secret,
 }
-impl ApiKey {
-    pub fn get_auth_header_tuple(&self) -> (String, String) {
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    /// API token, the recommended way to authenticate
+    BearerAuth(Secret),
+    ApiKey(ApiKey),
+    /// Origin CA key, sent as `X-Auth-User-Service-Key`
+    OriginCaKey(Secret),
+}
+impl Authorization {
+    /// Headers authenticating a request, failing if a secret has not been [read](Self::resolve_secrets)
+    pub fn headers(&self) -> Result<Vec<(&'static str, String)>, Error> {
+        Ok(match self {
+            Self::BearerAuth(token) => {
+                vec![("Authorization", format!("Bearer {}", token.expose()?))]
+            }
+            Self::ApiKey(ApiKey { email, key }) => vec![
+                ("X-Auth-Email", email.expose()?.to_owned()),
+                ("X-Auth-Key", key.expose()?.to_owned()),
+            ],
+            Self::OriginCaKey(key) => {
+                vec![("X-Auth-User-Service-Key", key.expose()?.to_owned())]
+            }
+        })
+    }
+
+    /// Reads the secrets that are not written into the config file
+    pub async fn resolve_secrets(&mut self) -> Result<(), Error> {
         match self {
-            Self::Email(token) => ("X-Auth-Email".to_owned(), token.to_string()),
-            Self::Key(token) => ("X-Auth-Key".to_owned(), token.to_string()),
-            Self::UserServiceKey(token) => {
-                ("X-Auth-User-Service-Key".to_owned(), token.to_string())
+            Self::BearerAuth(secret) | Self::OriginCaKey(secret) => secret.resolve().await,
+            Self::ApiKey(ApiKey { email, key }) => {
+                email.resolve().await?;
+                key.resolve().await
+            }
+        }
+    }
+
+    /// Replaces the secrets written into the config with `<redacted>`
+    pub fn redact(&mut self) {
+        match self {
+            Self::BearerAuth(secret) | Self::OriginCaKey(secret) => secret.redact(),
+            Self::ApiKey(ApiKey { email, key }) => {
+                email.redact();
+                key.redact();
             }
         }
     }
 }
-#[derive(Serialize, Deserialize)]
-pub enum Authorization {
-    BearerAuth(String),
-    ApiKey(ApiKey),
+
+/// Credentials shared by the zones of an account
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Account {
+    pub auth: Authorization,
+    /// Id of the account, zones configured by name are only looked up in this account if set
+    pub id: Option<String>,
+}
+
+/// How the results of multiple ip services are combined
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Use the first service that returns an address, trying them in order
+    #[default]
+    first_success,
+    /// Require `quorum` services to return the same address
+    quorum,
+    /// Require all services to return the same address
+    all_agree,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceList {
+    pub services: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Number of services that have to agree for the `quorum` strategy, defaults to a majority
+    pub quorum: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum IpService {
+    Single(String),
+    Multiple(ServiceList),
+}
+impl IpService {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            Self::Single(url) => std::slice::from_ref(url),
+            Self::Multiple(list) => &list.services,
+        }
+    }
+    pub fn strategy(&self) -> Strategy {
+        match self {
+            Self::Single(_) => Strategy::first_success,
+            Self::Multiple(list) => list.strategy,
+        }
+    }
+    /// Number of services that have to return the same address
+    pub fn required_agreement(&self) -> usize {
+        let n = self.urls().len();
+        match self {
+            Self::Multiple(ServiceList {
+                strategy: Strategy::quorum,
+                quorum,
+                ..
+            }) => quorum.unwrap_or(n / 2 + 1),
+            Self::Multiple(ServiceList {
+                strategy: Strategy::all_agree,
+                ..
+            }) => n,
+            _ => 1,
+        }
+    }
+}
+
+/// Reads the address from a local network interface instead of asking a service
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InterfaceSource {
+    pub name: String,
+    /// Only use addresses inside this prefix
+    pub prefix: Option<IpPrefix>,
+    /// Only use IPv6 addresses with this interface identifier (the lower 64 bits), e.g. an EUI-64 suffix
+    pub suffix: Option<Ipv6Addr>,
+    /// Also use private, carrier-grade NAT and unique local addresses, e.g. for records only resolved inside a network
+    pub allow_private: Option<bool>,
+}
+impl InterfaceSource {
+    pub fn allow_private(&self) -> bool {
+        self.allow_private.unwrap_or(false)
+    }
+}
+
+/// Retries of failed requests to the Cloudflare API
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Attempts made per request, including the first one
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry, doubled with every further retry
+    pub backoff: Option<HumanDuration>,
+    /// Time after the first attempt after which no more retries are started
+    pub deadline: Option<HumanDuration>,
+}
+impl RetryConfig {
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1)
+    }
+    pub fn backoff(&self) -> Duration {
+        self.backoff.map_or(DEFAULT_BACKOFF, |b| b.0)
+    }
+    pub fn deadline(&self) -> Duration {
+        self.deadline.map_or(DEFAULT_DEADLINE, |d| d.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
 pub struct Config {
-    pub ipv4_service: Option<String>,
-    pub ipv6_service: Option<String>,
+    pub ipv4_service: Option<IpService>,
+    pub ipv6_service: Option<IpService>,
+
+    /// Interfaces the addresses are read from, the services are only used as fallback
+    pub ipv4_interface: Option<InterfaceSource>,
+    pub ipv6_interface: Option<InterfaceSource>,
+
+    /// Base url of the Cloudflare API, e.g. to use a proxy or a local mock
+    pub api_url: Option<String>,
+
+    /// How often the addresses are checked in daemon mode
+    pub interval: Option<HumanDuration>,
+
+    /// File used to remember the last addresses and patched records between runs
+    pub state_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Limit of requests sent to the Cloudflare API at the same time, across all zones
+    pub max_concurrent_requests: Option<usize>,
+
+    /// Credentials shared by several zones, keyed by a name the zones refer to
+    #[serde(default)]
+    pub accounts: BTreeMap<String, Account>,
 
     pub zones: Vec<Zone>,
 }
+impl Zone {
+    /// Id of the zone. Zones configured by name have to be resolved with
+    /// [`resolve_zones`](crate::api::resolve_zones) first.
+    pub fn id(&self) -> &str {
+        &self
+            .identifier
+            .as_ref()
+            .expect("Zone id should be resolved before use")
+            .0
+    }
+
+    /// Credentials of the zone. Zones of an account get them by
+    /// [`apply_defaults`](Config::apply_defaults).
+    pub fn auth(&self) -> &Authorization {
+        self.auth
+            .as_ref()
+            .expect("Zone should have auth or an account")
+    }
+
+    /// Whether the zone is configured with the given name or identifier
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name)
+            || self.identifier.as_ref().is_some_and(|id| id.0 == name)
+    }
+
+    /// Name of the zone if configured, its id otherwise
+    pub fn display_name(&self) -> &str {
+        match (&self.name, &self.identifier) {
+            (Some(name), _) => name,
+            (None, Some(id)) => &id.0,
+            (None, None) => "(unnamed zone)",
+        }
+    }
+
+    pub fn api_url(&self) -> &str {
+        self.api_url
+            .as_deref()
+            .unwrap_or(DEFAULT_API_URL)
+            .trim_end_matches('/')
+    }
+
+    /// Addresses the records of this zone are set to, using the fallback addresses
+    /// for missing address families if the zone replaces them. Families whose detection failed are not replaced.
+    pub fn addresses(
+        &self,
+        addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+        failed: FailedFamilies,
+    ) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        match self.missing_family {
+            MissingFamilyPolicy::replace => (
+                addresses.0.or(self.ipv4_fallback.filter(|_| !failed.ipv4)),
+                addresses.1.or(self.ipv6_fallback.filter(|_| !failed.ipv6)),
+            ),
+            _ => addresses,
+        }
+    }
+}
+
+impl Config {
+    /// Passes settings made for all zones down to the zones that do not override them
+    pub fn apply_defaults(&mut self) {
+        for zone in &mut self.zones {
+            if zone.api_url.is_none() {
+                zone.api_url.clone_from(&self.api_url);
+            }
+            if let Some(account) = zone.account.as_ref().and_then(|a| self.accounts.get(a)) {
+                if zone.auth.is_none() {
+                    zone.auth = Some(account.auth.clone());
+                }
+                zone.account_id.clone_from(&account.id);
+            }
+        }
+    }
+
+    /// Writes the defaults of all unset settings into the config, so that it shows the values
+    /// actually used. Also passes settings down to the zones like [`apply_defaults`](Self::apply_defaults).
+    pub fn fill_defaults(&mut self) {
+        self.api_url
+            .get_or_insert_with(|| DEFAULT_API_URL.to_owned());
+        self.interval = Some(HumanDuration(self.interval()));
+        self.max_concurrent_requests = Some(self.max_concurrent_requests());
+        self.retry.max_attempts = Some(self.retry.max_attempts());
+        self.retry.backoff = Some(HumanDuration(self.retry.backoff()));
+        self.retry.deadline = Some(HumanDuration(self.retry.deadline()));
+        self.apply_defaults();
+    }
+
+    /// Replaces the secrets written into the config with `<redacted>`, keeping references to
+    /// environment variables, files and systemd credentials
+    pub fn redact_secrets(&mut self) {
+        let accounts = self.accounts.values_mut().map(|a| &mut a.auth);
+        let zones = self.zones.iter_mut().filter_map(|z| z.auth.as_mut());
+        accounts.chain(zones).for_each(Authorization::redact);
+    }
+
+    /// Reads the secrets of all accounts and zones that are not written into the config file
+    pub async fn resolve_secrets(&mut self) -> Result<(), Error> {
+        let with_location = |location: String| {
+            move |e| match e {
+                Error::Config(m) => Error::Config(format!("{location}: {m}")),
+                e => e,
+            }
+        };
+        for (name, account) in &mut self.accounts {
+            account
+                .auth
+                .resolve_secrets()
+                .await
+                .map_err(with_location(format!("Account \"{name}\"")))?;
+        }
+        for zone in &mut self.zones {
+            if let Some(auth) = &mut zone.auth {
+                auth.resolve_secrets()
+                    .await
+                    .map_err(with_location(format!("Zone \"{}\"", zone.display_name())))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks settings that depend on each other, failing with the first error found by [`problems`](Self::problems)
+    pub fn validate(&self) -> Result<(), Error> {
+        Self::first_error(self.problems())
+    }
+
+    /// Checks only the settings used to detect the addresses, ignoring problems of the zones and accounts
+    pub fn validate_addresses(&self) -> Result<(), Error> {
+        Self::first_error(
+            self.problems()
+                .into_iter()
+                .filter(|p| !matches!(p.path.first(), Some(Key("zones") | Key("accounts")))),
+        )
+    }
+
+    fn first_error(problems: impl IntoIterator<Item = Problem>) -> Result<(), Error> {
+        match problems.into_iter().find(|p| p.severity == Severity::Error) {
+            Some(problem) => Err(Error::Config(problem.message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Finds invalid settings, and rules that probably match more records than intended as warnings
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if self.ipv4_service.is_none()
+            && self.ipv6_service.is_none()
+            && self.ipv4_interface.is_none()
+            && self.ipv6_interface.is_none()
+        {
+            problems.push(Problem::error(
+                Vec::new(),
+                "At least one of ipv4_service, ipv6_service, ipv4_interface and ipv6_interface has to be set".to_owned(),
+            ));
+        }
+        for (key, service) in [
+            ("ipv4_service", &self.ipv4_service),
+            ("ipv6_service", &self.ipv6_service),
+        ] {
+            let Some(IpService::Multiple(list)) = service else {
+                continue;
+            };
+            if list.services.is_empty() {
+                problems.push(Problem::error(
+                    vec![Key(key), Key("services")],
+                    format!("{key} needs at least one service"),
+                ));
+            } else if let Some(quorum) = list
+                .quorum
+                .filter(|q| !(1..=list.services.len()).contains(q))
+            {
+                problems.push(Problem::error(
+                    vec![Key(key), Key("quorum")],
+                    format!(
+                        "{key}: quorum has to be between 1 and the number of services ({}), not {quorum}",
+                        list.services.len()
+                    ),
+                ));
+            }
+        }
+        if self.zones.is_empty() {
+            problems.push(Problem::error(
+                vec![Key("zones")],
+                "At least one zone has to be configured".to_owned(),
+            ));
+        }
+        for (z, zone) in self.zones.iter().enumerate() {
+            let name = zone.display_name();
+            let path = |rest: &[PathSegment]| [&[Key("zones"), Index(z)], rest].concat();
+            if zone.identifier.is_none() && zone.name.is_none() {
+                problems.push(Problem::error(
+                    path(&[]),
+                    "Every zone needs either an identifier or a name".to_owned(),
+                ));
+            }
+            match &zone.account {
+                Some(account) if !self.accounts.contains_key(account) => {
+                    let message = format!(
+                        "Zone \"{name}\" refers to account \"{account}\", which is not configured"
+                    );
+                    problems.push(Problem::error(path(&[Key("account")]), message))
+                }
+                None if zone.auth.is_none() => problems.push(Problem::error(
+                    path(&[]),
+                    format!("Zone \"{name}\" needs either auth or an account"),
+                )),
+                _ => {}
+            }
+            for (i, rule) in zone.search.iter().enumerate() {
+                let location = format!("Zone \"{name}\", rule {i}");
+                let rule_path =
+                    |rest: &[PathSegment]| path(&[&[Key("search"), Index(i)], rest].concat());
+                if let Some(e) = ipv6_rewrite_problem(rule.ipv6_suffix, rule.ipv6_prefix_length) {
+                    problems.push(Problem::error(
+                        rule_path(&[Key("ipv6_prefix_length")]),
+                        format!("{location}: {e}"),
+                    ));
+                }
+                if let Some(Err(e)) = rule.template.as_deref().map(template::validate) {
+                    problems.push(Problem::error(
+                        rule_path(&[Key("template")]),
+                        format!("{location}: {e}"),
+                    ));
+                }
+                if let Some(Err(e)) = rule.set.comment.as_deref().map(template::validate) {
+                    problems.push(Problem::error(
+                        rule_path(&[Key("set"), Key("comment")]),
+                        format!("{location}: {e}"),
+                    ));
+                }
+                let too_broad = match (&rule.r#type, &rule.template) {
+                    _ if rule.filters_records() => None,
+                    (None, _) => Some("matches every record of the zone"),
+                    (Some(_), Some(_)) => {
+                        Some("sets every record of its type to the same template")
+                    }
+                    (Some(_), None) => None,
+                };
+                if let Some(message) = too_broad {
+                    problems.push(Problem::warning(
+                        rule_path(&[]),
+                        format!("{location} {message}"),
+                    ));
+                }
+            }
+            for (i, ensure) in zone.ensure.iter().enumerate() {
+                let location = format!("Zone \"{name}\", record {}", ensure.name);
+                let ensure_path = |key| path(&[Key("ensure"), Index(i), Key(key)]);
+                // Zones configured by identifier have no name to compare with
+                if let Some(zone_name) = &zone.name {
+                    if !is_in_zone(&ensure.name.0, zone_name) {
+                        problems.push(Problem::error(
+                            ensure_path("name"),
+                            format!("{location}: Name is not {zone_name} or a subdomain of it"),
+                        ));
+                    }
+                }
+                if let Some(e) = ipv6_rewrite_problem(ensure.ipv6_suffix, ensure.ipv6_prefix_length)
+                {
+                    problems.push(Problem::error(
+                        ensure_path("ipv6_prefix_length"),
+                        format!("{location}: {e}"),
+                    ));
+                }
+                if let Some(Err(e)) = ensure.comment.as_deref().map(template::validate) {
+                    problems.push(Problem::error(
+                        ensure_path("comment"),
+                        format!("{location}: {e}"),
+                    ));
+                }
+            }
+            if let Some(Err(e)) = zone.set.comment.as_deref().map(template::validate) {
+                problems.push(Problem::error(
+                    path(&[Key("set"), Key("comment")]),
+                    format!("Zone \"{name}\": {e}"),
+                ));
+            }
+            if zone.missing_family == MissingFamilyPolicy::replace
+                && zone.ipv4_fallback.is_none()
+                && zone.ipv6_fallback.is_none()
+            {
+                problems.push(Problem::error(
+                    path(&[Key("missing_family")]),
+                    format!("Zone \"{name}\" replaces missing address families, but has neither an ipv4_fallback nor an ipv6_fallback"),
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Keeps only the zones with the given names or identifiers, all zones if none are given
+    pub fn select_zones(&mut self, names: &[String]) -> Result<(), Error> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        if let Some(unknown) = names
+            .iter()
+            .find(|n| !self.zones.iter().any(|z| z.is_named(n)))
+        {
+            return Err(Error::Config(format!(
+                "No zone \"{unknown}\" is configured"
+            )));
+        }
+        self.zones.retain(|z| names.iter().any(|n| z.is_named(n)));
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval.map_or(DEFAULT_INTERVAL, |i| i.0)
+    }
+
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.max_concurrent_requests
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+            .max(1)
+    }
+}
+
+/// Reads the text of the config file
+pub(crate) async fn read_config_file(config_path: &Path) -> Result<String, Error> {
+    log::info!(
+        "Opening config file at {}",
+        config_path.to_str().unwrap_or("(Non utf-8 string)")
+    );
+    let mut config_file = File::open(config_path)
+        .await
+        .map_err(|e| Error::Config(format!("Could not open config file: {e}")))?;
+
+    let mut config_string = String::new();
+    config_file
+        .read_to_string(&mut config_string)
+        .await
+        .map_err(|e| Error::Config(format!("Could not read config file: {e}")))?;
+    Ok(config_string)
+}
+
+/// Reads a single config file, see [`read_config_from`]
+pub async fn read_config(config_path: &Path) -> Result<Config, Error> {
+    read_config_from(&ConfigSource::File(config_path.to_owned()), &[]).await
+}
+
+/// Reads the config from a file or a directory of fragments and applies the overrides, with later
+/// overrides taking precedence. Then reads its secrets and validates it.
+pub async fn read_config_from(
+    source: &ConfigSource,
+    overrides: &[Overrides],
+) -> Result<Config, Error> {
+    let mut conf = config_from_value(source.read_value_with(overrides).await?)?;
+    // Secrets are read before they are copied from the accounts to their zones, so each is only read once
+    conf.resolve_secrets().await?;
+    conf.apply_defaults();
+    conf.validate()?;
+    Ok(conf)
+}
+
+/// Reads the config like [`read_config_from`], but only to detect the addresses: no secrets are read
+/// and the zones are not validated, so the addresses can be shown before the zones are usable.
+pub async fn read_address_config(
+    source: &ConfigSource,
+    overrides: &[Overrides],
+) -> Result<Config, Error> {
+    let mut conf = config_from_value(source.read_value_with(overrides).await?)?;
+    conf.apply_defaults();
+    conf.validate_addresses()?;
+    Ok(conf)
+}
+
+/// Reads the config like [`read_config_from`], but without reading any secrets. All defaults are
+/// filled in and the secrets written into the config are redacted, so the result can be shown.
+pub async fn read_effective_config(
+    source: &ConfigSource,
+    overrides: &[Overrides],
+) -> Result<Config, Error> {
+    let mut conf = config_from_value(source.read_value_with(overrides).await?)?;
+    conf.fill_defaults();
+    conf.redact_secrets();
+    Ok(conf)
+}
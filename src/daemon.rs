@@ -0,0 +1,153 @@
+//! Daemon mode, updating the zones periodically until a shutdown signal is received
+
+use std::future::Future;
+
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use crate::{
+    api,
+    client::{ClientContext, Clients},
+    config::Config,
+    error::Error,
+    report::RunSummary,
+    state::State,
+};
+
+/// Registers the handlers of the shutdown signals right away, so a signal sent during startup
+/// is not missed. The returned future completes once a signal is received.
+#[cfg(unix)]
+fn shutdown_signal() -> std::io::Result<impl Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => log::info!("Received SIGTERM"),
+            _ = interrupt.recv() => log::info!("Received SIGINT"),
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> std::io::Result<impl Future<Output = ()>> {
+    Ok(async {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => log::info!("Received Ctrl-C"),
+            Err(e) => {
+                log::error!("Could not listen for Ctrl-C: {e}");
+                std::future::pending().await
+            }
+        }
+    })
+}
+
+/// One update cycle of the daemon, run on every tick of its interval
+pub trait Update {
+    fn update(&mut self) -> impl Future<Output = Result<(), Error>>;
+}
+
+/// Updates the zones of a config with the current addresses, keeping the state between cycles
+struct ZoneUpdater<'a> {
+    conf: &'a mut Config,
+    clients: &'a Clients,
+    state: State,
+}
+impl Update for ZoneUpdater<'_> {
+    async fn update(&mut self) -> Result<(), Error> {
+        log::info!("Getting ip addresses");
+        let detected = api::get_ip_addresses(&*self.conf, &self.clients.context().http).await?;
+        log::info!("Got {}", api::address_tuple_to_string(detected.addresses()));
+
+        let result = api::patch_zones(
+            &mut self.conf.zones,
+            self.clients,
+            detected.addresses(),
+            detected.failed(),
+            &mut self.state,
+            &mut RunSummary::default(),
+        )
+        .await;
+
+        if let Some(path) = &self.conf.state_file {
+            if let Err(e) = self.state.save(path).await {
+                log::error!("Could not save state file: {e}");
+            }
+        }
+        result
+    }
+}
+
+/// Runs an update on every tick until `shutdown` completes. A failing update is logged and
+/// retried on the next tick.
+///
+/// The shutdown is only acted upon between two updates, so patches that are already in flight
+/// are always finished.
+pub async fn update_until(
+    ticker: &mut Interval,
+    shutdown: impl Future<Output = ()>,
+    updater: &mut impl Update,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut shutdown => break,
+            _ = ticker.tick() => {}
+        }
+        if let Err(e) = updater.update().await {
+            log::warn!("Update failed, retrying next interval: {e}");
+        }
+    }
+    log::info!("Shutting down");
+}
+
+/// Periodically checks the ip addresses and patches all zones that are not up to date with them,
+/// until a shutdown signal is received.
+///
+/// A signal received during startup ends the daemon right away, see [`update_until`] for
+/// signals received later.
+pub async fn run(mut conf: Config) -> Result<(), Error> {
+    let clients = Clients::new(ClientContext::from_config(&conf), &conf.zones);
+    let period = conf.interval();
+
+    log::info!(
+        "Running as daemon, checking addresses every {}",
+        humantime::format_duration(period)
+    );
+
+    let shutdown = shutdown_signal().map_err(|source| Error::Io {
+        context: "Could not listen for shutdown signals",
+        source,
+    })?;
+    tokio::pin!(shutdown);
+
+    let mut ticker = interval(period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut state = match &conf.state_file {
+        Some(path) => State::load(path).await,
+        None => State::default(),
+    };
+    // Startup retries rate limited requests, which can take long enough to be interrupted
+    let startup = async {
+        api::verify_credentials(&conf.zones, &clients).await?;
+        api::resolve_zones(&mut conf.zones, &clients, &mut state).await
+    };
+    tokio::select! {
+        biased;
+        _ = &mut shutdown => {
+            log::info!("Shutting down during startup");
+            return Ok(());
+        }
+        r = startup => r?,
+    }
+
+    let mut updater = ZoneUpdater {
+        conf: &mut conf,
+        clients: &clients,
+        state,
+    };
+    update_until(&mut ticker, shutdown, &mut updater).await;
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+
+use crate::records::Message;
+
+/// Cloudflare error codes reporting an invalid or insufficient token or api key
+const AUTH_ERROR_CODES: [u32; 7] = [6003, 6111, 9103, 9106, 9109, 10000, 10001];
+/// Cloudflare error code reporting that a record does not exist
+const RECORD_NOT_FOUND_CODE: u32 = 81044;
+/// Cloudflare error code reporting that a request could not be routed, e.g. as the zone id does not exist
+const ZONE_NOT_FOUND_CODE: u32 = 7003;
+/// Cloudflare error code reporting that too many requests have been made
+const RATE_LIMITED_CODE: u32 = 971;
+
+/// Errors returned by the library, classified so callers can react to each kind of failure
+#[derive(Debug)]
+pub enum Error {
+    /// The config file could not be read or is invalid
+    Config(String),
+    /// The state file could not be written, or another io operation failed
+    Io {
+        context: &'static str,
+        source: std::io::Error,
+    },
+    /// No usable address could be determined
+    IpDetection(String),
+    /// The API rejected the token or api key
+    Auth { messages: Vec<Message> },
+    /// The API rate limit has been reached
+    RateLimited { retry_after: Option<Duration> },
+    /// The zone or record does not exist
+    NotFound { messages: Vec<Message> },
+    /// The API reported the request as unsuccessful for any other reason
+    ApiError {
+        code: StatusCode,
+        messages: Vec<Message>,
+    },
+    /// The request could not be sent or the response could not be read
+    Transport(reqwest::Error),
+    /// The response body could not be decoded
+    Decode(serde_json::Error),
+    /// The search rule could not be serialized into url parameters
+    Encode(serde_url_params::Error),
+    /// A successful response did not contain a result
+    MissingResult,
+}
+
+impl Error {
+    /// Classifies an unsuccessful response by its status code and the errors reported by the API
+    pub fn from_response(
+        code: StatusCode,
+        messages: Vec<Message>,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let has_code = |codes: &[u32]| messages.iter().any(|m| codes.contains(&m.code));
+        match code {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Auth { messages },
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { retry_after },
+            StatusCode::NOT_FOUND => Self::NotFound { messages },
+            _ if has_code(&AUTH_ERROR_CODES) => Self::Auth { messages },
+            _ if has_code(&[RATE_LIMITED_CODE]) => Self::RateLimited { retry_after },
+            _ if has_code(&[RECORD_NOT_FOUND_CODE, ZONE_NOT_FOUND_CODE]) => {
+                Self::NotFound { messages }
+            }
+            _ => Self::ApiError { code, messages },
+        }
+    }
+
+    /// Exit code of the binary when failing with this error.
+    /// 1 is left for unexpected errors and 2 for invalid command line arguments.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) => 3,
+            Self::Io { .. } => 4,
+            Self::IpDetection(_) => 5,
+            Self::Auth { .. } => 6,
+            Self::RateLimited { .. } => 7,
+            Self::NotFound { .. } => 8,
+            Self::ApiError { .. } => 9,
+            Self::Transport(_) => 10,
+            Self::Decode(_) | Self::Encode(_) | Self::MissingResult => 11,
+        }
+    }
+}
+
+fn write_messages(f: &mut Formatter<'_>, messages: &[Message]) -> std::fmt::Result {
+    for (i, m) in messages.iter().enumerate() {
+        write!(f, "{}{m}", if i == 0 { ": " } else { ", " })?;
+    }
+    Ok(())
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "{e}"),
+            Self::Io { context, source } => write!(f, "{context}: {source}"),
+            Self::IpDetection(e) => write!(f, "{e}"),
+            Self::Auth { messages } => {
+                write!(f, "Authentication failed")?;
+                write_messages(f, messages)
+            }
+            Self::RateLimited { retry_after } => {
+                write!(f, "Rate limited")?;
+                match retry_after {
+                    Some(d) => write!(f, ", retry after {}", humantime::format_duration(*d)),
+                    None => Ok(()),
+                }
+            }
+            Self::NotFound { messages } => {
+                write!(f, "Not found")?;
+                write_messages(f, messages)
+            }
+            Self::ApiError { code, messages } => {
+                write!(f, "Request unsuccessful with code {code}")?;
+                write_messages(f, messages)
+            }
+            Self::Transport(e) => write!(f, "Error while sending request: {e}"),
+            Self::Decode(e) => write!(f, "Could not decode response: {e}"),
+            Self::Encode(e) => write!(f, "Could not serialize url parameters: {e}"),
+            Self::MissingResult => write!(f, "Response does not contain a result"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Transport(e) => Some(e),
+            Self::Decode(e) => Some(e),
+            Self::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Transport(e)
+    }
+}
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Decode(e)
+    }
+}
+impl From<serde_url_params::Error> for Error {
+    fn from(e: serde_url_params::Error) -> Self {
+        Self::Encode(e)
+    }
+}
@@ -0,0 +1,259 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{config::InterfaceSource, error::Error};
+
+// Address flags as listed in /proc/net/if_inet6 (see linux/if_addr.h)
+const IFA_F_TEMPORARY: u8 = 0x01;
+const IFA_F_DADFAILED: u8 = 0x08;
+const IFA_F_DEPRECATED: u8 = 0x20;
+const IFA_F_TENTATIVE: u8 = 0x40;
+
+/// An IPv6 address of an interface together with its kernel flags
+#[derive(Debug, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub addr: Ipv6Addr,
+    pub flags: u8,
+}
+
+/// Parses the addresses of the named interface from the contents of /proc/net/if_inet6
+pub fn parse_if_inet6(text: &str, interface: &str) -> Vec<InterfaceAddress> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [addr, _index, _prefix_len, _scope, flags, name] if name == interface => {
+                    Some(InterfaceAddress {
+                        addr: u128::from_str_radix(addr, 16).ok()?.into(),
+                        flags: u8::from_str_radix(flags, 16).ok()?,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn is_global_ipv6(addr: &Ipv6Addr, allow_private: bool) -> bool {
+    let first = addr.segments()[0];
+    !(addr.is_loopback()
+        || addr.is_unspecified()
+        || addr.is_multicast()
+        // Link-local fe80::/10
+        || first & 0xffc0 == 0xfe80
+        // Unique local fc00::/7
+        || (!allow_private && first & 0xfe00 == 0xfc00))
+}
+
+fn is_global_ipv4(addr: &Ipv4Addr, allow_private: bool) -> bool {
+    let [first, second, ..] = addr.octets();
+    !(addr.is_loopback()
+        || addr.is_unspecified()
+        || addr.is_link_local()
+        || addr.is_multicast()
+        || addr.is_broadcast()
+        || (!allow_private
+            && (addr.is_private()
+                // Shared address space of carrier-grade NAT 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64))))
+}
+
+fn matches_source(addr: IpAddr, source: &InterfaceSource) -> bool {
+    if let Some(prefix) = &source.prefix {
+        if !prefix.contains(addr) {
+            return false;
+        }
+    }
+    match (addr, source.suffix) {
+        // The suffix is compared against the 64 bit interface identifier
+        (IpAddr::V6(a), Some(suffix)) => {
+            u128::from(a) & u64::MAX as u128 == u128::from(suffix) & u64::MAX as u128
+        }
+        _ => true,
+    }
+}
+
+/// Picks the first usable IPv4 address, skipping loopback and link-local addresses, private
+/// and carrier-grade NAT addresses unless allowed, as well as those not matching the source's filters
+pub fn select_ipv4_address(addresses: &[Ipv4Addr], source: &InterfaceSource) -> Option<Ipv4Addr> {
+    addresses.iter().copied().find(|a| {
+        is_global_ipv4(a, source.allow_private()) && matches_source(IpAddr::V4(*a), source)
+    })
+}
+
+/// Picks the first usable IPv6 address, skipping link-local, temporary, deprecated and tentative
+/// addresses, unique local addresses unless allowed, as well as those not matching the source's filters
+pub fn select_ipv6_address(
+    addresses: &[InterfaceAddress],
+    source: &InterfaceSource,
+) -> Option<Ipv6Addr> {
+    addresses
+        .iter()
+        .filter(|a| {
+            a.flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED | IFA_F_TENTATIVE | IFA_F_DADFAILED) == 0
+        })
+        .map(|a| a.addr)
+        .find(|a| {
+            is_global_ipv6(a, source.allow_private()) && matches_source(IpAddr::V6(*a), source)
+        })
+}
+
+#[cfg(unix)]
+fn interface_addresses(interface: &str) -> Result<Vec<IpAddr>, Error> {
+    let addresses = nix::ifaddrs::getifaddrs()
+        .map_err(|e| Error::IpDetection(format!("Could not list interface addresses: {e}")))?
+        .filter(|i| i.interface_name == interface)
+        .filter_map(|i| {
+            let address = i.address?;
+            if let Some(v4) = address.as_sockaddr_in() {
+                Some(IpAddr::V4(v4.ip()))
+            } else {
+                address.as_sockaddr_in6().map(|v6| IpAddr::V6(v6.ip()))
+            }
+        })
+        .collect();
+    Ok(addresses)
+}
+
+#[cfg(not(unix))]
+fn interface_addresses(_interface: &str) -> Result<Vec<IpAddr>, Error> {
+    Err(Error::IpDetection(
+        "Reading interface addresses is not supported on this platform".to_owned(),
+    ))
+}
+
+/// The usable IPv4 address of the interface, `None` if it has none
+pub fn get_ipv4_address(source: &InterfaceSource) -> Result<Option<Ipv4Addr>, Error> {
+    let addresses: Vec<Ipv4Addr> = interface_addresses(&source.name)?
+        .into_iter()
+        .filter_map(|a| match a {
+            IpAddr::V4(v4) => Some(v4),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+    Ok(select_ipv4_address(&addresses, source))
+}
+
+/// The usable IPv6 address of the interface, `None` if it has none
+pub fn get_ipv6_address(source: &InterfaceSource) -> Result<Option<Ipv6Addr>, Error> {
+    // Only procfs exposes whether an address is temporary or deprecated
+    let addresses = if cfg!(target_os = "linux") {
+        let text = std::fs::read_to_string("/proc/net/if_inet6")
+            .map_err(|e| Error::IpDetection(format!("Could not read /proc/net/if_inet6: {e}")))?;
+        parse_if_inet6(&text, &source.name)
+    } else {
+        interface_addresses(&source.name)?
+            .into_iter()
+            .filter_map(|a| match a {
+                IpAddr::V6(addr) => Some(InterfaceAddress { addr, flags: 0 }),
+                IpAddr::V4(_) => None,
+            })
+            .collect()
+    };
+    Ok(select_ipv6_address(&addresses, source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IF_INET6: &str = "\
+fe80000000000000021122fffe334455 02 40 20 80     eth0
+fd000000000000000000000000000001 02 40 00 80     eth0
+20010db8000000001c2d3e4f5a6b7c8d 02 40 00 01     eth0
+20010db8000000000000000000000099 02 40 00 a0     eth0
+20010db800000000021122fffe334455 02 40 00 00     eth0
+20010db800000000021122fffe334455 02 40 00 00     eth1
+00000000000000000000000000000001 01 80 10 80       lo
+";
+
+    fn source(toml: &str) -> InterfaceSource {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn addresses() -> Vec<InterfaceAddress> {
+        parse_if_inet6(IF_INET6, "eth0")
+    }
+
+    #[test]
+    fn parses_addresses_of_interface() {
+        let addresses = addresses();
+        assert_eq!(addresses.len(), 5);
+        assert_eq!(
+            addresses[0],
+            InterfaceAddress {
+                addr: "fe80::211:22ff:fe33:4455".parse().unwrap(),
+                flags: 0x80,
+            }
+        );
+    }
+
+    #[test]
+    fn skips_local_temporary_and_deprecated_addresses() {
+        assert_eq!(
+            select_ipv6_address(&addresses(), &source("name = \"eth0\"")),
+            Some("2001:db8::211:22ff:fe33:4455".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn filters_by_prefix_and_suffix() {
+        let addresses = addresses();
+        assert_eq!(
+            select_ipv6_address(
+                &addresses,
+                &source("name = \"eth0\"\nsuffix = \"::211:22ff:fe33:4455\"")
+            ),
+            Some("2001:db8::211:22ff:fe33:4455".parse().unwrap())
+        );
+        assert_eq!(
+            select_ipv6_address(&addresses, &source("name = \"eth0\"\nsuffix = \"::1\"")),
+            None
+        );
+        assert_eq!(
+            select_ipv6_address(
+                &addresses,
+                &source("name = \"eth0\"\nprefix = \"2001:db9::/32\"")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn skips_private_ipv4_addresses_unless_allowed() {
+        let addresses: Vec<Ipv4Addr> = [
+            "127.0.0.1",
+            "169.254.1.1",
+            "192.168.1.2",
+            "10.0.0.2",
+            "172.16.0.2",
+            "100.64.0.2",
+            "198.51.100.7",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        assert_eq!(
+            select_ipv4_address(&addresses, &source("name = \"eth0\"")),
+            Some(Ipv4Addr::new(198, 51, 100, 7))
+        );
+        assert_eq!(
+            select_ipv4_address(&addresses[..6], &source("name = \"eth0\"")),
+            None
+        );
+        assert_eq!(
+            select_ipv4_address(&addresses, &source("name = \"eth0\"\nallow_private = true")),
+            Some(Ipv4Addr::new(192, 168, 1, 2))
+        );
+    }
+
+    #[test]
+    fn unique_local_ipv6_addresses_can_be_allowed() {
+        assert_eq!(
+            select_ipv6_address(
+                &addresses(),
+                &source("name = \"eth0\"\nallow_private = true")
+            ),
+            Some("fd00::1".parse().unwrap())
+        );
+    }
+}
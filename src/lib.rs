@@ -0,0 +1,21 @@
+//! DynDNS client for Cloudflare
+//!
+//! Besides the command line client, this crate provides a [`CloudflareClient`](client::CloudflareClient)
+//! for the DNS record endpoints of the Cloudflare API and the building blocks used to detect
+//! the current addresses and update records with them.
+
+pub mod api;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod daemon;
+pub mod error;
+pub mod interface;
+pub mod loader;
+pub mod misc_serialization;
+pub mod plan;
+pub mod records;
+pub mod report;
+pub mod secret;
+pub mod state;
+pub mod template;
@@ -0,0 +1,329 @@
+//! Reading the config from TOML, YAML or JSON files and from directories of config fragments.
+//!
+//! Files are read into a generic value first, so that fragments can be merged before the
+//! [`Config`] is built from them.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{Map, Value};
+
+use crate::{
+    check::Diagnostic,
+    config::{read_config_file, Config},
+    error::Error,
+    secret::Secret,
+};
+
+/// Format of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+impl ConfigFormat {
+    /// Detects the format by the extension of the file, files without `.yaml`, `.yml` or `.json` extension are TOML
+    pub fn of(path: &Path) -> Self {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        match extension.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Self::Yaml,
+            "json" => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    /// Parses the text into a generic value, locating syntax errors
+    pub fn parse(self, text: &str) -> Result<Value, Diagnostic> {
+        match self {
+            Self::Toml => toml::from_str(text).map_err(|e| Diagnostic::from_parse_error(text, &e)),
+            Self::Yaml => serde_yaml::from_str(text).map_err(|e| {
+                let position = e.location().map(|l| (l.line(), l.column()));
+                Diagnostic::at(position, without_position(e.to_string(), position))
+            }),
+            Self::Json => serde_json::from_str(text).map_err(|e| {
+                let position = Some((e.line(), e.column())).filter(|(line, _)| *line > 0);
+                Diagnostic::at(position, without_position(e.to_string(), position))
+            }),
+        }
+    }
+}
+
+/// Removes the position YAML and JSON errors include in their message, as it is reported separately
+fn without_position(message: String, position: Option<(usize, usize)>) -> String {
+    match position {
+        Some((line, column)) => {
+            message.replacen(&format!(" at line {line} column {column}"), "", 1)
+        }
+        None => message,
+    }
+}
+
+/// Turns a syntax error of a config file into an error
+fn parse_error(d: Diagnostic) -> Error {
+    match (d.line, d.column) {
+        (Some(line), Some(column)) => Error::Config(format!(
+            "Could not parse config file at line {line}, column {column}: {}",
+            d.message
+        )),
+        _ => Error::Config(format!("Could not parse config file: {}", d.message)),
+    }
+}
+
+/// Where the config is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// A single file in any [`ConfigFormat`]
+    File(PathBuf),
+    /// A directory of TOML fragments, merged in the order of their names
+    Dir(PathBuf),
+}
+impl ConfigSource {
+    /// The files of the source with their text, in the order they are merged
+    pub async fn read_files(&self) -> Result<Vec<(PathBuf, String)>, Error> {
+        let paths = match self {
+            Self::File(path) => vec![path.clone()],
+            Self::Dir(dir) => fragments(dir).await?,
+        };
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let text = read_config_file(&path).await?;
+            files.push((path, text));
+        }
+        Ok(files)
+    }
+
+    /// Whether the config comes from the overrides only, as the config file does not exist
+    /// but the environment or the command line configure something
+    pub async fn overrides_only(&self, overrides: &[Overrides]) -> bool {
+        match self {
+            Self::File(path)
+                if overrides.iter().any(|o| !o.is_empty())
+                    && !tokio::fs::try_exists(path).await.unwrap_or(true) =>
+            {
+                log::info!(
+                    "Config file {} does not exist, using the environment and command line only",
+                    path.display()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Reads and merges the files of the source into a generic value
+    pub async fn read_value(&self) -> Result<Value, Error> {
+        let mut value = Value::Object(Map::new());
+        for (path, text) in self.read_files().await? {
+            let fragment = ConfigFormat::of(&path)
+                .parse(&text)
+                .map_err(parse_error)
+                .map_err(|e| match (e, self) {
+                    (Error::Config(m), Self::Dir(_)) => {
+                        Error::Config(format!("{}: {m}", path.display()))
+                    }
+                    (e, _) => e,
+                })?;
+            merge(&mut value, fragment);
+        }
+        Ok(value)
+    }
+
+    /// Reads the config like [`read_value`](Self::read_value) and applies the overrides in order,
+    /// so later overrides take precedence. A missing config file is treated as empty if there is anything to override.
+    pub async fn read_value_with(&self, overrides: &[Overrides]) -> Result<Value, Error> {
+        let mut value = match self.overrides_only(overrides).await {
+            true => Value::Object(Map::new()),
+            false => self.read_value().await?,
+        };
+        for o in overrides {
+            o.apply(&mut value);
+        }
+        Ok(value)
+    }
+}
+
+/// The `*.toml` files of a directory, sorted by name
+async fn fragments(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let read_error = |e| {
+        Error::Config(format!(
+            "Could not read config directory {}: {e}",
+            dir.display()
+        ))
+    };
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(read_error)?;
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(read_error)? {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "toml") && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    if paths.is_empty() {
+        return Err(Error::Config(format!(
+            "Config directory {} contains no .toml files",
+            dir.display()
+        )));
+    }
+    Ok(paths)
+}
+
+/// Merges a fragment into the config read so far. The zones of the fragment are appended,
+/// the entries of top-level tables like `accounts` or `retry` are added or replaced one by one,
+/// and all other top-level values replace the earlier ones.
+pub fn merge(config: &mut Value, fragment: Value) {
+    let (Value::Object(config), Value::Object(fragment)) = (config, fragment) else {
+        return;
+    };
+    for (key, value) in fragment {
+        match (config.get_mut(&key), value) {
+            (Some(Value::Array(zones)), Value::Array(more)) if key == "zones" => zones.extend(more),
+            (Some(Value::Object(table)), Value::Object(entries)) => table.extend(entries),
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Builds the config from a generic value, naming the path of the invalid value on failure
+pub fn config_from_value(value: Value) -> Result<Config, Error> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        match path.as_str() {
+            "." => Error::Config(format!("Invalid config: {}", e.inner())),
+            _ => Error::Config(format!("Invalid config at {path}: {}", e.inner())),
+        }
+    })
+}
+
+pub const IPV4_SERVICE_VAR: &str = "CF_DYNDNS_IPV4_SERVICE";
+pub const IPV6_SERVICE_VAR: &str = "CF_DYNDNS_IPV6_SERVICE";
+pub const ZONE_ID_VAR: &str = "CF_DYNDNS_ZONE_ID";
+pub const TOKEN_VAR: &str = "CF_DYNDNS_TOKEN";
+pub const RECORDS_VAR: &str = "CF_DYNDNS_RECORDS";
+
+/// A zone given by its id, an API token and the names of its records, without a config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneOverride {
+    pub identifier: String,
+    pub token: Secret,
+    /// Names of the records updated with the detected addresses
+    pub records: Vec<String>,
+}
+impl ZoneOverride {
+    fn to_value(&self) -> Value {
+        let search: Vec<Value> = self
+            .records
+            .iter()
+            .map(|name| serde_json::json!({ "name": name }))
+            .collect();
+        serde_json::json!({
+            "identifier": self.identifier,
+            "auth": { "BearerAuth": self.token },
+            "search": search,
+        })
+    }
+}
+
+/// Settings that override the config files, given as environment variables or command line arguments
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Overrides {
+    pub ipv4_service: Option<String>,
+    pub ipv6_service: Option<String>,
+    /// Replaces the zone with the same identifier, or is added to the zones of the config files
+    pub zone: Option<ZoneOverride>,
+}
+impl Overrides {
+    /// Reads the `CF_DYNDNS_*` variables of the environment
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Reads the `CF_DYNDNS_*` variables from a list of names and values, ignoring empty values
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, Error> {
+        let vars: BTreeMap<String, String> = vars
+            .into_iter()
+            .filter(|(name, value)| name.starts_with("CF_DYNDNS_") && !value.is_empty())
+            .collect();
+        let zone = match (
+            vars.get(ZONE_ID_VAR),
+            vars.get(TOKEN_VAR),
+            vars.get(RECORDS_VAR),
+        ) {
+            (None, None, None) => None,
+            (Some(identifier), Some(token), Some(records)) => Some(ZoneOverride {
+                identifier: identifier.clone(),
+                token: Secret::Plain(token.clone()),
+                records: records
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|r| !r.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            }),
+            _ => {
+                let missing: Vec<&str> = [ZONE_ID_VAR, TOKEN_VAR, RECORDS_VAR]
+                    .into_iter()
+                    .filter(|name| !vars.contains_key(*name))
+                    .collect();
+                let verb = if missing.len() == 1 { "is" } else { "are" };
+                return Err(Error::Config(format!(
+                    "{ZONE_ID_VAR}, {TOKEN_VAR} and {RECORDS_VAR} have to be set together, {} {verb} not set",
+                    missing.join(" and ")
+                )));
+            }
+        };
+        Ok(Self {
+            ipv4_service: vars.get(IPV4_SERVICE_VAR).cloned(),
+            ipv6_service: vars.get(IPV6_SERVICE_VAR).cloned(),
+            zone,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ipv4_service.is_none() && self.ipv6_service.is_none() && self.zone.is_none()
+    }
+
+    /// Sets the overridden values in the config read so far
+    pub fn apply(&self, config: &mut Value) {
+        let Value::Object(config) = config else {
+            return;
+        };
+        if let Some(url) = &self.ipv4_service {
+            config.insert("ipv4_service".to_owned(), Value::String(url.clone()));
+        }
+        if let Some(url) = &self.ipv6_service {
+            config.insert("ipv6_service".to_owned(), Value::String(url.clone()));
+        }
+        if let Some(zone) = &self.zone {
+            let zones = config
+                .entry("zones")
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(zones) = zones {
+                let same = zones.iter_mut().find(|z| {
+                    z.get("identifier").and_then(Value::as_str) == Some(&zone.identifier)
+                });
+                match same {
+                    Some(same) => *same = zone.to_value(),
+                    None => zones.push(zone.to_value()),
+                }
+            }
+        }
+    }
+}
+
+/// Removes unset values, which can be written in YAML and JSON but not in TOML
+pub fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(remove_nulls),
+        Value::Object(table) => {
+            table.retain(|_, v| !v.is_null());
+            table.values_mut().for_each(remove_nulls);
+        }
+        _ => {}
+    }
+}
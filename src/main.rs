@@ -1,61 +1,334 @@
-use clap::Parser;
-use config::Config;
+use clap::{Parser, Subcommand, ValueEnum};
+use cloudflare_dyndns::{
+    api::{self, DetectedAddresses},
+    check::{self, Severity},
+    client::{ClientContext, Clients},
+    config::{read_address_config, read_config_from, read_effective_config, Config},
+    daemon,
+    error::Error,
+    loader::{self, ConfigSource, Overrides},
+    plan::Plan,
+    report::{AddressReport, RecordListing, RunSummary, ZoneAddresses},
+    state::State,
+};
+use serde::Serialize;
 use simple_logger::SimpleLogger;
-use std::error::Error;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::process::ExitCode;
+use std::{fmt::Display, path::PathBuf};
 
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-mod api;
-mod config;
-mod misc_serialization;
-mod records;
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Table,
+    Json,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct CliArgs {
-    #[arg(short,long,default_value=clap::builder::OsStr::from("config.toml"))]
+    #[arg(short, long, global = true, default_value = "config.toml")]
     config: PathBuf,
+
+    /// Directory of TOML config fragments, merged in the order of their names, used instead of the config file
+    #[arg(long, global = true, conflicts_with = "config")]
+    config_dir: Option<PathBuf>,
+
+    /// Service the IPv4 address is detected with, overriding the config and CF_DYNDNS_IPV4_SERVICE
+    #[arg(long, global = true, value_name = "URL")]
+    ipv4_service: Option<String>,
+
+    /// Service the IPv6 address is detected with, overriding the config and CF_DYNDNS_IPV6_SERVICE
+    #[arg(long, global = true, value_name = "URL")]
+    ipv6_service: Option<String>,
+
+    /// Print the effective config with secrets redacted, instead of running a command
+    #[arg(long, global = true)]
+    print_config: bool,
+
+    /// Only use the zone with this name or identifier, can be given multiple times
+    #[arg(long, global = true)]
+    zone: Vec<String>,
+
+    /// Output format of the result, `run` only prints a summary if this is given
+    #[arg(long, global = true, value_enum)]
+    output: Option<OutputFormat>,
+
+    /// Same as `run --daemon`
+    #[arg(short, long, hide = true)]
+    daemon: bool,
+
+    /// Same as `plan`
+    #[arg(long, hide = true, conflicts_with = "daemon")]
+    dry_run: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn read_config(config_path: PathBuf) -> Result<Config, Box<dyn Error>> {
-    log::info!(
-        "Opening config file at {}",
-        config_path.to_str().unwrap_or("(Non utf-8 string)")
-    );
-    let mut config_file = File::open(config_path)
-        .await
-        .or_else(|e| Err(format!("Could not open config file: {e}")))?;
+impl CliArgs {
+    fn config_source(&self) -> ConfigSource {
+        match &self.config_dir {
+            Some(dir) => ConfigSource::Dir(dir.clone()),
+            None => ConfigSource::File(self.config.clone()),
+        }
+    }
 
-    let mut config_string = String::new();
-    config_file
-        .read_to_string(&mut config_string)
-        .await
-        .or_else(|e| Err(format!("Could not read config file: {e}")))?;
+    /// Settings of the command line that take precedence over the environment and the config files
+    fn overrides(&self) -> Overrides {
+        Overrides {
+            ipv4_service: self.ipv4_service.clone(),
+            ipv6_service: self.ipv6_service.clone(),
+            zone: None,
+        }
+    }
+}
 
-    toml::from_str(&config_string)
-        .or_else(|e| Err(format!("Could not parse config file: {e}").into()))
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Patch the records with the current addresses (the default)
+    Run {
+        /// Keep running and patch the records whenever the addresses change
+        #[arg(short, long)]
+        daemon: bool,
+    },
+    /// List the records matched by each search rule
+    List,
+    /// Only print the changes that would be made, without patching any records
+    Plan,
+    /// Check the config for errors and overly broad search rules, without contacting any service
+    ///
+    /// Problems are reported with their line and column. In YAML and JSON files, only syntax errors
+    /// are located; other problems are reported with the file they were found in.
+    Check,
+    /// Only print the detected addresses
+    ShowIp,
 }
 
-async fn patch_config(conf: Config) -> Result<(), Box<dyn Error>> {
-    let client = Arc::new(reqwest::Client::new());
+/// Prints the result of a command in the chosen format
+fn print_output<T: Serialize + Display>(value: &T, output: OutputFormat) {
+    match output {
+        OutputFormat::Table => print!("{value}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(value).expect("Output should be serializable")
+        ),
+    }
+}
+
+async fn load_state(conf: &Config) -> State {
+    match &conf.state_file {
+        Some(path) => State::load(path).await,
+        None => State::default(),
+    }
+}
+
+/// Creates the clients of the zones and checks their credentials
+async fn connect(conf: &Config) -> Result<Clients, Error> {
+    let clients = Clients::new(ClientContext::from_config(conf), &conf.zones);
+    api::verify_credentials(&conf.zones, &clients).await?;
+    Ok(clients)
+}
+
+async fn get_ip_addresses(conf: &Config, clients: &Clients) -> Result<DetectedAddresses, Error> {
     log::info!("Getting ip addresses");
+    let detected = api::get_ip_addresses(conf, &clients.context().http).await?;
+    log::info!("Got {}", api::address_tuple_to_string(detected.addresses()));
+    Ok(detected)
+}
+
+async fn patch_config(mut conf: Config, output: Option<OutputFormat>) -> Result<(), Error> {
+    let clients = Clients::new(ClientContext::from_config(&conf), &conf.zones);
+    let mut state = load_state(&conf).await;
+    let detected = get_ip_addresses(&conf, &clients).await?;
+    api::verify_credentials_for_run(
+        &conf.zones,
+        &clients,
+        &state,
+        detected.addresses(),
+        detected.failed(),
+    )
+    .await?;
+    api::resolve_zones(&mut conf.zones, &clients, &mut state).await?;
 
-    let addr = api::get_ip_addresses(conf.ipv4_service, conf.ipv6_service, client.clone()).await?;
-    log::info!("Got {}", api::address_tuple_to_string(addr));
+    let mut summary = RunSummary::default();
+    let result = api::patch_zones(
+        &mut conf.zones,
+        &clients,
+        detected.addresses(),
+        detected.failed(),
+        &mut state,
+        &mut summary,
+    )
+    .await;
 
-    Ok(for zone in conf.zones {
-        let id = &zone.identifier.clone();
-        match api::patch_zone(zone, client.clone(), addr).await {
-            Ok(i) => log::info!("(\"{id}\"): Patched {i} records"),
-            Err(e) => log::error!("\"{id}\": Error while patching records: {e}"),
-        };
-    })
+    if let Some(path) = &conf.state_file {
+        state.save(path).await?;
+    }
+    if let Some(output) = output {
+        print_output(&summary, output);
+    }
+    result
 }
 
-async fn async_main(cli: CliArgs) -> Result<(), Box<dyn Error>> {
-    let conf = read_config(cli.config).await?;
+async fn list_config(mut conf: Config, output: OutputFormat) -> Result<(), Error> {
+    let clients = connect(&conf).await?;
+    // Zone ids looked up here are not saved, listing records never changes the state file
+    let mut state = load_state(&conf).await;
+    api::resolve_zones(&mut conf.zones, &clients, &mut state).await?;
+
+    let mut listing = RecordListing::default();
+    for zone in &mut conf.zones {
+        let client = clients.for_zone(zone);
+        let mut result = api::list_zone(zone, &client, &mut listing).await;
+        if matches!(result, Err(Error::NotFound { .. }))
+            && api::refresh_zone_id(zone, &client, &mut state).await
+        {
+            result = api::list_zone(zone, &client, &mut listing).await;
+        }
+        result?;
+    }
+    print_output(&listing, output);
+    Ok(())
+}
+
+async fn plan_config(mut conf: Config, output: OutputFormat) -> Result<(), Error> {
+    let clients = connect(&conf).await?;
+    // Zone ids looked up here are not saved, a dry run never changes the state file
+    let mut state = load_state(&conf).await;
+    api::resolve_zones(&mut conf.zones, &clients, &mut state).await?;
+
+    let detected = get_ip_addresses(&conf, &clients).await?;
+    let (addr, failed) = (detected.addresses(), detected.failed());
+
+    let mut plan = Plan::default();
+    for zone in &mut conf.zones {
+        let client = clients.for_zone(zone);
+        let mut result = api::plan_zone(zone, &client, addr, failed, &mut plan).await;
+        if matches!(result, Err(Error::NotFound { .. }))
+            && api::refresh_zone_id(zone, &client, &mut state).await
+        {
+            result = api::plan_zone(zone, &client, addr, failed, &mut plan).await;
+        }
+        result?;
+    }
+    plan.sort();
+    print_output(&plan, output);
+    Ok(())
+}
+
+async fn show_ip(conf: Config, selected: &[String], output: OutputFormat) -> Result<(), Error> {
+    let http = ClientContext::from_config(&conf).http;
+    let detected = api::get_ip_addresses(&conf, &http).await?;
+    let (ipv4, ipv6) = detected.addresses();
+    // The fallbacks of the zones are only shown for the zones asked for
+    let zones = match selected {
+        [] => Vec::new(),
+        _ => conf
+            .zones
+            .iter()
+            .map(|zone| {
+                let (ipv4, ipv6) = zone.addresses((ipv4, ipv6), detected.failed());
+                ZoneAddresses {
+                    zone: zone.display_name().to_owned(),
+                    ipv4,
+                    ipv6,
+                }
+            })
+            .collect(),
+    };
+    print_output(&AddressReport { ipv4, ipv6, zones }, output);
+    Ok(())
+}
+
+/// Prints the problems of the config files, failing if any of them is an error
+async fn check_config(
+    source: &ConfigSource,
+    overrides: &[Overrides],
+    zones: &[String],
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let diagnostics = check::check_config(source, overrides, zones).await?;
+    match output {
+        OutputFormat::Table => {
+            for d in &diagnostics {
+                let path = match (&d.file, source) {
+                    (Some(file), _) => file,
+                    (None, ConfigSource::File(path) | ConfigSource::Dir(path)) => path,
+                };
+                match d.line {
+                    Some(_) => println!("{}:{d}", path.display()),
+                    None => println!("{}: {d}", path.display()),
+                }
+            }
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&diagnostics).expect("Diagnostics should be serializable")
+        ),
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        return Err(Error::Config(format!(
+            "Config has {errors} errors and {warnings} warnings"
+        )));
+    }
+    log::info!("Config is valid ({warnings} warnings)");
+    Ok(())
+}
+
+/// Prints the effective config with its defaults and secrets redacted, without reading secrets or validating the settings
+async fn print_config(
+    source: &ConfigSource,
+    overrides: &[Overrides],
+    output: OutputFormat,
+) -> Result<(), Error> {
+    let conf = read_effective_config(source, overrides).await?;
+    let mut value = serde_json::to_value(&conf).expect("Config should be serializable");
+    match output {
+        OutputFormat::Table => {
+            loader::remove_nulls(&mut value);
+            print!(
+                "{}",
+                toml::to_string(&value).expect("Config should be serializable as TOML")
+            );
+        }
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&value).expect("Config should be serializable")
+        ),
+    }
+    Ok(())
+}
+
+async fn async_main(cli: CliArgs) -> Result<(), Error> {
+    let source = cli.config_source();
+    // Later overrides take precedence: command line > environment > config files
+    let overrides = [Overrides::from_env()?, cli.overrides()];
+    let command = match cli.command {
+        Some(Command::Run { daemon }) => Command::Run {
+            daemon: daemon || cli.daemon,
+        },
+        Some(command) => command,
+        None if cli.dry_run => Command::Plan,
+        None => Command::Run { daemon: cli.daemon },
+    };
+    let output = cli.output.unwrap_or(OutputFormat::Table);
+    if cli.print_config {
+        return print_config(&source, &overrides, output).await;
+    }
+    if let Command::Check = command {
+        return check_config(&source, &overrides, &cli.zone, output).await;
+    }
+    if let Command::ShowIp = command {
+        let mut conf = read_address_config(&source, &overrides).await?;
+        conf.select_zones(&cli.zone)?;
+        return show_ip(conf, &cli.zone, output).await;
+    }
+    let mut conf = read_config_from(&source, &overrides).await?;
+    conf.select_zones(&cli.zone)?;
 
     let mut total_search_fields = 0;
     for zone in &conf.zones {
@@ -68,10 +341,17 @@ async fn async_main(cli: CliArgs) -> Result<(), Box<dyn Error>> {
         total_search_fields
     );
 
-    patch_config(conf).await
+    match command {
+        Command::Run { daemon: true } => daemon::run(conf).await,
+        Command::Run { daemon: false } => patch_config(conf, cli.output).await,
+        Command::List => list_config(conf, output).await,
+        Command::Plan => plan_config(conf, output).await,
+        Command::Check => unreachable!("The config is checked without reading it"),
+        Command::ShowIp => unreachable!("The addresses are shown without reading the zones"),
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> ExitCode {
     SimpleLogger::new()
         .with_level(log::LevelFilter::Info)
         .env()
@@ -81,9 +361,17 @@ fn main() -> Result<(), Box<dyn Error>> {
     log::debug!("Parsing CLI args");
     let cli = CliArgs::parse();
     log::debug!("CLI Args: {:?}", cli);
-    tokio::runtime::Builder::new_multi_thread()
+    let result = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(async_main(cli))
+        .block_on(async_main(cli));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use core::fmt::{Display, Formatter};
+use std::{net::IpAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
@@ -63,7 +64,7 @@ impl<const MIN: u32, const MAX: u32> TryFrom<u32> for MinMaxValueU32<MIN, MAX> {
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(try_from = "u32")]
 pub struct TTLU32(pub u32);
 impl TryFrom<u32> for TTLU32 {
@@ -71,8 +72,85 @@ impl TryFrom<u32> for TTLU32 {
     fn try_from(v: u32) -> Result<Self, Self::Error> {
         match v {
             1 => Ok(Self(1)),
-            v if v >= 30 && v <= 86400 => Ok(Self(v)),
+            v if (30..=86400).contains(&v) => Ok(Self(v)),
             _ => Err(format!("Invalid TTL int: {v}"))?,
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct HumanDuration(pub Duration);
+impl TryFrom<String> for HumanDuration {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match humantime::parse_duration(&s) {
+            Ok(d) if d.is_zero() => Err(format!("Duration '{s}' must not be zero")),
+            Ok(d) => Ok(Self(d)),
+            Err(e) => Err(format!("Invalid duration '{s}': {e}")),
+        }
+    }
+}
+impl From<HumanDuration> for String {
+    fn from(d: HumanDuration) -> Self {
+        humantime::format_duration(d.0).to_string()
+    }
+}
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        humantime::format_duration(self.0).fmt(f)
+    }
+}
+
+/// An address prefix in CIDR notation like `2001:db8::/32` or `192.0.2.0/24`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+impl IpPrefix {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(p), IpAddr::V4(a)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(p) & mask == u32::from(a) & mask
+            }
+            (IpAddr::V6(p), IpAddr::V6(a)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(p) & mask == u128::from(a) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl TryFrom<String> for IpPrefix {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (addr, len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Prefix '{s}' is missing a prefix length"))?;
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid prefix address '{addr}': {e}"))?;
+        let len: u8 = len
+            .parse()
+            .map_err(|e| format!("Invalid prefix length '{len}': {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if len > max {
+            Err(format!("Prefix length {len} exceeds maximum of {max}"))
+        } else {
+            Ok(Self { addr, len })
+        }
+    }
+}
+impl From<IpPrefix> for String {
+    fn from(p: IpPrefix) -> Self {
+        p.to_string()
+    }
+}
+impl Display for IpPrefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{
+        EnsureRecord, FailedFamilies, IpRecordType, MissingFamilyPolicy, RecordFields,
+        RecordSettings,
+    },
+    records::{RecordPatch, RecordResponse, TypeSpecificData},
+    report::write_table,
+    template::{self, TemplateValues},
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    Unchanged,
+    NoIpv4Address,
+    NoIpv6Address,
+    Ipv4DetectionFailed,
+    Ipv6DetectionFailed,
+    NotAnIpRecord,
+    NoHostname,
+    InvalidTemplate,
+    NotTemplatable,
+}
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unchanged => "Content has not changed",
+            Self::NoIpv4Address => "Cannot update record as no IPv4 address is provided",
+            Self::NoIpv6Address => "Cannot update record as no IPv6 address is provided",
+            Self::Ipv4DetectionFailed => "Keeping record as the IPv4 address could not be detected",
+            Self::Ipv6DetectionFailed => "Keeping record as the IPv6 address could not be detected",
+            Self::NotAnIpRecord => "Record is not an IP record and has no template",
+            Self::NoHostname => "Cannot render template as the host name is unknown",
+            Self::InvalidTemplate => "Cannot render invalid template",
+            Self::NotTemplatable => "Templates can not be used for records of this type",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Sets the content if it changed and the other fields in `fields`
+    Patch {
+        content: Option<String>,
+        fields: RecordPatch,
+    },
+    Create {
+        content: String,
+    },
+    Delete,
+    Skip(SkipReason),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Patch,
+    Create,
+    Delete,
+}
+
+/// Decides what has to be done to bring a record up to date with the addresses.
+///
+/// Records of an address family that is missing are deleted if the policy says so, records of a
+/// family whose detection failed are always kept. The `replace` policy is applied by passing the addresses through [`Zone::addresses`](crate::config::Zone::addresses) first.
+///
+/// Records matched by a rule with a template are set to the rendered template instead,
+/// those are skipped rather than deleted if a value used by the template is missing.
+/// The other fields set by the rule are patched along with the content, also on records that
+/// are not IP records and have no template.
+pub fn plan_record(
+    record: &RecordResponse,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    policy: MissingFamilyPolicy,
+    failed: FailedFamilies,
+    settings: &RecordSettings,
+) -> Action {
+    let values = TemplateValues {
+        ipv4: addresses.0,
+        ipv6: addresses.1,
+        hostname: template::hostname(),
+        name: &record.name.0,
+        now: SystemTime::now(),
+    };
+    let content = match settings.template.as_deref() {
+        Some(template) => plan_template(record, &values, template),
+        None => plan_address(record, addresses, policy, failed),
+    };
+    // Fields are still patched if only the content is up to date or not managed
+    let (content, unchanged) = match content {
+        Action::Patch { content, .. } => (content, SkipReason::Unchanged),
+        Action::Skip(reason @ (SkipReason::Unchanged | SkipReason::NotAnIpRecord)) => {
+            (None, reason)
+        }
+        action => return action,
+    };
+    let fields = match plan_fields(record, &settings.fields, &values) {
+        Ok(f) => f,
+        Err(reason) => return Action::Skip(reason),
+    };
+    if content.is_none() && fields == RecordPatch::default() {
+        return Action::Skip(unchanged);
+    }
+    match render_templates(record, settings, &values, content, fields) {
+        Ok((content, fields)) => Action::Patch { content, fields },
+        Err(reason) => Action::Skip(reason),
+    }
+}
+
+/// Renders the templates of a record that is patched anyway, so that e.g. a timestamp is updated
+/// along with the other changes. Templates are only compared by [`template::matches`] before,
+/// which accepts any timestamp.
+fn render_templates(
+    record: &RecordResponse,
+    settings: &RecordSettings,
+    values: &TemplateValues,
+    content: Option<String>,
+    mut fields: RecordPatch,
+) -> Result<(Option<String>, RecordPatch), SkipReason> {
+    let content = match (content, settings.template.as_deref()) {
+        (None, Some(template)) => Some(template::render(template, values)?)
+            .filter(|c| Some(c.as_str()) != record.type_data.templated_value()),
+        (content, _) => content,
+    };
+    if let (None, Some(comment)) = (&fields.comment, &settings.fields.comment) {
+        let rendered = template::render(comment, values)?;
+        if record.comment.as_deref().unwrap_or_default() != rendered {
+            fields.comment = Some(rendered);
+        }
+    }
+    Ok((content, fields))
+}
+
+fn plan_address(
+    record: &RecordResponse,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    policy: MissingFamilyPolicy,
+    failed: FailedFamilies,
+) -> Action {
+    let missing = |reason| match policy {
+        MissingFamilyPolicy::delete => Action::Delete,
+        _ => Action::Skip(reason),
+    };
+    let new_content = match &record.type_data {
+        TypeSpecificData::A { .. } => match addresses.0 {
+            Some(v) => v.to_string(),
+            None if failed.ipv4 => return Action::Skip(SkipReason::Ipv4DetectionFailed),
+            None => return missing(SkipReason::NoIpv4Address),
+        },
+        TypeSpecificData::AAAA { .. } => match addresses.1 {
+            Some(v) => v.to_string(),
+            None if failed.ipv6 => return Action::Skip(SkipReason::Ipv6DetectionFailed),
+            None => return missing(SkipReason::NoIpv6Address),
+        },
+        _ => return Action::Skip(SkipReason::NotAnIpRecord),
+    };
+    if record.type_data.content() == new_content {
+        Action::Skip(SkipReason::Unchanged)
+    } else {
+        Action::Patch {
+            content: Some(new_content),
+            fields: RecordPatch::default(),
+        }
+    }
+}
+
+fn plan_template(record: &RecordResponse, values: &TemplateValues, template: &str) -> Action {
+    let Some(current) = record.type_data.templated_value() else {
+        return Action::Skip(SkipReason::NotTemplatable);
+    };
+    let rendered = template::matches(template, values, current)
+        .and_then(|unchanged| Ok((unchanged, template::render(template, values)?)));
+    match rendered {
+        Ok((true, _)) => Action::Skip(SkipReason::Unchanged),
+        Ok((false, content)) => Action::Patch {
+            content: Some(content),
+            fields: RecordPatch::default(),
+        },
+        Err(reason) => Action::Skip(reason),
+    }
+}
+
+/// The fields of the record that differ from the configured ones
+fn plan_fields(
+    record: &RecordResponse,
+    fields: &RecordFields,
+    values: &TemplateValues,
+) -> Result<RecordPatch, SkipReason> {
+    let mut patch = RecordPatch::default();
+    if fields.ttl.is_some() && fields.ttl != record.ttl {
+        patch.ttl = fields.ttl;
+    }
+    // Types that can not be proxied are left alone, so one rule can match records of several types
+    if let (Some(proxied), Some(current)) = (fields.proxied, record.type_data.proxied()) {
+        if proxied != current {
+            patch.proxied = Some(proxied);
+        }
+    }
+    if let Some(comment) = &fields.comment {
+        let current = record.comment.as_deref().unwrap_or_default();
+        if !template::matches(comment, values, current)? {
+            patch.comment = Some(template::render(comment, values)?);
+        }
+    }
+    if let Some(tags) = &fields.tags {
+        let mut wanted: Vec<&String> = tags.iter().collect();
+        let mut current: Vec<&String> = record.tags.iter().flatten().collect();
+        wanted.sort();
+        current.sort();
+        if wanted != current {
+            patch.tags = Some(tags.clone());
+        }
+    }
+    Ok(patch)
+}
+
+/// Whether the record is one of the listed records
+fn is_ensured_record(ensure: &EnsureRecord, record: &RecordResponse) -> bool {
+    record.type_data.type_name() == ensure.r#type.name()
+        && record.name.0.eq_ignore_ascii_case(&ensure.name.0)
+}
+
+/// Decides whether a record that has to exist must be created.
+/// Returns `None` if it is one of the listed records, which are planned by [`plan_record`].
+pub fn plan_ensure<'a>(
+    ensure: &EnsureRecord,
+    mut records: impl Iterator<Item = &'a RecordResponse>,
+    addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+) -> Option<Action> {
+    if records.any(|r| is_ensured_record(ensure, r)) {
+        return None;
+    }
+    let content = match ensure.r#type {
+        IpRecordType::A => match addresses.0 {
+            Some(v) => v.to_string(),
+            None => return Some(Action::Skip(SkipReason::NoIpv4Address)),
+        },
+        IpRecordType::AAAA => match addresses.1 {
+            Some(v) => v.to_string(),
+            None => return Some(Action::Skip(SkipReason::NoIpv6Address)),
+        },
+    };
+    Some(Action::Create { content })
+}
+
+/// A change of an existing record, or a record that will be created if `id` is `None`
+#[derive(Serialize, Debug)]
+pub struct PlannedChange {
+    pub action: ChangeKind,
+    pub zone: String,
+    pub id: Option<String>,
+    pub name: String,
+    pub r#type: &'static str,
+    pub current: Option<String>,
+    /// Content after the change, `None` if the record is deleted
+    pub new: Option<String>,
+    /// Other fields changed along with the content
+    #[serde(skip_serializing_if = "is_default")]
+    pub fields: RecordPatch,
+}
+
+fn is_default(patch: &RecordPatch) -> bool {
+    *patch == RecordPatch::default()
+}
+
+/// Names of the fields set by the patch, for the table output
+fn field_names(patch: &RecordPatch) -> Vec<&'static str> {
+    [
+        ("ttl", patch.ttl.is_some()),
+        ("proxied", patch.proxied.is_some()),
+        ("comment", patch.comment.is_some()),
+        ("tags", patch.tags.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect()
+}
+
+#[derive(Serialize, Debug)]
+pub struct SkippedRecord {
+    pub zone: String,
+    pub id: Option<String>,
+    pub name: String,
+    pub r#type: &'static str,
+    pub content: Option<String>,
+    pub reason: SkipReason,
+}
+
+/// Changes that would be made to the records of all zones
+#[derive(Serialize, Default, Debug)]
+pub struct Plan {
+    pub changes: Vec<PlannedChange>,
+    pub skipped: Vec<SkippedRecord>,
+}
+impl Plan {
+    pub fn add(&mut self, zone: &str, record: &RecordResponse, action: Action) {
+        self.push(
+            zone,
+            Some(record.id.to_string()),
+            record.name.to_string(),
+            record.type_data.type_name(),
+            Some(
+                record
+                    .type_data
+                    .templated_value()
+                    .unwrap_or(record.type_data.content())
+                    .to_owned(),
+            ),
+            action,
+        );
+    }
+
+    /// Adds a record that has to exist but was not found
+    pub fn add_missing(&mut self, zone: &str, ensure: &EnsureRecord, action: Action) {
+        self.push(
+            zone,
+            None,
+            ensure.name.to_string(),
+            ensure.r#type.name(),
+            None,
+            action,
+        );
+    }
+
+    fn push(
+        &mut self,
+        zone: &str,
+        id: Option<String>,
+        name: String,
+        r#type: &'static str,
+        current: Option<String>,
+        action: Action,
+    ) {
+        let zone = zone.to_owned();
+        let (action, new, fields) = match action {
+            Action::Patch { content, fields } => (
+                ChangeKind::Patch,
+                content.or_else(|| current.clone()),
+                fields,
+            ),
+            Action::Create { content } => (ChangeKind::Create, Some(content), Default::default()),
+            Action::Delete => (ChangeKind::Delete, None, Default::default()),
+            Action::Skip(reason) => {
+                self.skipped.push(SkippedRecord {
+                    zone,
+                    id,
+                    name,
+                    r#type,
+                    content: current,
+                    reason,
+                });
+                return;
+            }
+        };
+        self.changes.push(PlannedChange {
+            action,
+            zone,
+            id,
+            name,
+            r#type,
+            current,
+            new,
+            fields,
+        });
+    }
+
+    /// Sorts the entries so that plans of different runs can be compared
+    pub fn sort(&mut self) {
+        self.changes
+            .sort_by(|a, b| (&a.zone, &a.name, &a.id).cmp(&(&b.zone, &b.name, &b.id)));
+        self.skipped
+            .sort_by(|a, b| (&a.zone, &a.name, &a.id).cmp(&(&b.zone, &b.name, &b.id)));
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.changes.is_empty() {
+            writeln!(f, "No changes")?;
+        } else {
+            writeln!(f, "Changes:")?;
+            let news: Vec<String> = self
+                .changes
+                .iter()
+                .map(|c| {
+                    let new = c.new.as_deref().unwrap_or("(deleted)");
+                    match &field_names(&c.fields)[..] {
+                        [] => new.to_owned(),
+                        names => format!("{new} (and {})", names.join(", ")),
+                    }
+                })
+                .collect();
+            let rows: Vec<Vec<&str>> = self
+                .changes
+                .iter()
+                .zip(&news)
+                .map(|(c, new)| {
+                    let current = c.current.as_deref().unwrap_or("(new record)");
+                    vec![&*c.zone, &*c.name, c.r#type, current, &**new]
+                })
+                .collect();
+            write_table(f, &["ZONE", "NAME", "TYPE", "CURRENT", "NEW"], &rows)?;
+        }
+        if !self.skipped.is_empty() {
+            let reasons: Vec<String> = self.skipped.iter().map(|s| s.reason.to_string()).collect();
+            writeln!(f, "\nSkipped:")?;
+            let rows: Vec<Vec<&str>> = self
+                .skipped
+                .iter()
+                .zip(&reasons)
+                .map(|(s, r)| {
+                    let content = s.content.as_deref().unwrap_or("(missing)");
+                    vec![&*s.zone, &*s.name, s.r#type, content, &**r]
+                })
+                .collect();
+            write_table(f, &["ZONE", "NAME", "TYPE", "CONTENT", "REASON"], &rows)?;
+        }
+        Ok(())
+    }
+}
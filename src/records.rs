@@ -1,6 +1,8 @@
+use core::fmt::{Display, Formatter};
+
 use serde::{Deserialize, Serialize};
 
-use crate::misc_serialization::{MaxLenString, MinMaxValueU16, TTLU32};
+use crate::misc_serialization::{MaxLenString, TTLU32};
 
 #[derive(Deserialize, Debug)]
 pub struct Meta {
@@ -8,7 +10,7 @@ pub struct Meta {
     pub source: Option<String>,
 }
 
-mod record_data {
+pub mod record_data {
     use serde::{Deserialize, Serialize};
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -45,7 +47,43 @@ mod record_data {
         pub value: Option<String>,
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    pub struct LOCData {
+        pub altitude: Option<f64>,
+        pub lat_degrees: Option<u8>,
+        pub lat_direction: Option<String>,
+        pub lat_minutes: Option<u8>,
+        pub lat_seconds: Option<f64>,
+        pub long_degrees: Option<u8>,
+        pub long_direction: Option<String>,
+        pub long_minutes: Option<u8>,
+        pub long_seconds: Option<f64>,
+        pub precision_horz: Option<f64>,
+        pub precision_vert: Option<f64>,
+        pub size: Option<f64>,
+    }
+    #[derive(Serialize, Deserialize, Default, Debug, Clone)]
+    pub struct SRVData {
+        pub port: Option<u16>,
+        pub priority: Option<u16>,
+        pub target: Option<String>,
+        pub weight: Option<u16>,
+    }
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    pub struct SSHFPData {
+        pub algorithm: Option<u8>,
+        pub fingerprint: Option<String>,
+        pub r#type: Option<u8>,
+    }
+    #[derive(Serialize, Deserialize, Default, Debug)]
+    pub struct TLSAData {
+        pub certificate: Option<String>,
+        pub matching_type: Option<u8>,
+        pub selector: Option<u8>,
+        pub usage: Option<u8>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct URIData {
         pub content: Option<String>,
         pub weight: Option<u16>,
@@ -60,10 +98,12 @@ use record_data::*;
 pub enum TypeSpecificData {
     A {
         content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         proxied: Option<bool>,
     },
     AAAA {
         content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         proxied: Option<bool>,
     },
     CAA {
@@ -78,6 +118,8 @@ pub enum TypeSpecificData {
     },
     CNAME {
         content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        proxied: Option<bool>,
     },
     DNSKEY {
         #[serde(skip_serializing)]
@@ -97,7 +139,9 @@ pub enum TypeSpecificData {
     LOC {
         #[serde(skip_serializing)]
         content: String,
-    }, //todo
+        #[serde(default)]
+        data: LOCData,
+    },
     MX {
         content: String,
         priority: u16,
@@ -119,11 +163,15 @@ pub enum TypeSpecificData {
     SRV {
         #[serde(skip_serializing)]
         content: String,
-    }, //todo
+        #[serde(default)]
+        data: SRVData,
+    },
     SSHFP {
         #[serde(skip_serializing)]
         content: String,
-    }, //todo
+        #[serde(default)]
+        data: SSHFPData,
+    },
     SVCB {
         #[serde(skip_serializing)]
         content: String,
@@ -131,7 +179,9 @@ pub enum TypeSpecificData {
     TLSA {
         #[serde(skip_serializing)]
         content: String,
-    }, //todo
+        #[serde(default)]
+        data: TLSAData,
+    },
     TXT {
         content: String,
     },
@@ -142,17 +192,119 @@ pub enum TypeSpecificData {
     },
 }
 
+impl TypeSpecificData {
+    pub fn content(&self) -> &str {
+        match self {
+            Self::A { content, .. }
+            | Self::AAAA { content, .. }
+            | Self::CAA { content, .. }
+            | Self::CERT { content, .. }
+            | Self::CNAME { content, .. }
+            | Self::DNSKEY { content, .. }
+            | Self::DS { content, .. }
+            | Self::HTTPS { content, .. }
+            | Self::LOC { content, .. }
+            | Self::MX { content, .. }
+            | Self::NAPTR { content, .. }
+            | Self::NS { content, .. }
+            | Self::PTR { content, .. }
+            | Self::SMIMEA { content, .. }
+            | Self::SRV { content, .. }
+            | Self::SSHFP { content, .. }
+            | Self::SVCB { content, .. }
+            | Self::TLSA { content, .. }
+            | Self::TXT { content, .. }
+            | Self::URI { content, .. } => content,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::A { .. } => "A",
+            Self::AAAA { .. } => "AAAA",
+            Self::CAA { .. } => "CAA",
+            Self::CERT { .. } => "CERT",
+            Self::CNAME { .. } => "CNAME",
+            Self::DNSKEY { .. } => "DNSKEY",
+            Self::DS { .. } => "DS",
+            Self::HTTPS { .. } => "HTTPS",
+            Self::LOC { .. } => "LOC",
+            Self::MX { .. } => "MX",
+            Self::NAPTR { .. } => "NAPTR",
+            Self::NS { .. } => "NS",
+            Self::PTR { .. } => "PTR",
+            Self::SMIMEA { .. } => "SMIMEA",
+            Self::SRV { .. } => "SRV",
+            Self::SSHFP { .. } => "SSHFP",
+            Self::SVCB { .. } => "SVCB",
+            Self::TLSA { .. } => "TLSA",
+            Self::TXT { .. } => "TXT",
+            Self::URI { .. } => "URI",
+        }
+    }
+
+    /// Whether the record is proxied, `None` for types that can not be proxied
+    pub fn proxied(&self) -> Option<bool> {
+        match self {
+            Self::A { proxied, .. } | Self::AAAA { proxied, .. } | Self::CNAME { proxied, .. } => {
+                Some(proxied.unwrap_or(false))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value a content template is rendered into: the content of records that only have a content,
+    /// the target of SRV records and the content of URI records. `None` for all other types.
+    pub fn templated_value(&self) -> Option<&str> {
+        match self {
+            Self::A { content, .. }
+            | Self::AAAA { content, .. }
+            | Self::CNAME { content, .. }
+            | Self::MX { content, .. }
+            | Self::NS { content }
+            | Self::PTR { content }
+            | Self::TXT { content } => Some(content),
+            Self::SRV { data, .. } => Some(data.target.as_deref().unwrap_or_default()),
+            Self::URI { data, .. } => Some(data.content.as_deref().unwrap_or_default()),
+            _ => None,
+        }
+    }
+
+    /// Patch setting the [`templated_value`](Self::templated_value) of the record to `value`.
+    /// Records with typed data are patched with all of their data, as the API replaces it as a whole.
+    pub fn templated_patch(&self, value: String) -> Option<RecordPatch> {
+        let data = match self {
+            Self::SRV { data, .. } => serde_json::to_value(SRVData {
+                target: Some(value),
+                ..data.clone()
+            }),
+            Self::URI { data, .. } => serde_json::to_value(URIData {
+                content: Some(value),
+                ..data.clone()
+            }),
+            _ => {
+                self.templated_value()?;
+                return Some(RecordPatch {
+                    content: Some(value),
+                    ..Default::default()
+                });
+            }
+        };
+        Some(RecordPatch {
+            data: Some(data.expect("Record data should be serializable")),
+            ..Default::default()
+        })
+    }
+}
+
 pub trait Record {
     fn get_type_data(&self) -> &TypeSpecificData;
 
     fn get_name(&self) -> &MaxLenString<255>;
     fn get_id(&self) -> Option<&MaxLenString<32>>;
 
-    #[allow(dead_code)]
     fn get_comment(&self) -> &Option<String>;
-    #[allow(dead_code)]
     fn get_tags(&self) -> &Option<Vec<String>>;
-    #[allow(dead_code)]
     fn get_ttl(&self) -> &Option<TTLU32>;
 }
 
@@ -202,37 +354,139 @@ impl Record for RecordResponse {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Message {
-    pub code: MinMaxValueU16<1000, { u16::MAX }>,
+    pub code: u32,
     pub message: String,
 }
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
 
 #[derive(Deserialize, Debug)]
 pub struct ResultInfo {
-    pub count: u16,
-    pub page: u16,
-    pub per_page: u16,
-    pub total_count: u16,
+    pub count: u32,
+    pub page: u32,
+    pub per_page: u32,
+    pub total_count: u32,
+    pub total_pages: Option<u32>,
+}
+impl ResultInfo {
+    /// Number of pages, computed from the counts if the response does not include it
+    pub fn total_pages(&self) -> u32 {
+        self.total_pages
+            .unwrap_or_else(|| self.total_count.div_ceil(self.per_page.max(1)))
+    }
 }
 
+/// Envelope around every result returned by the API
 #[derive(Deserialize, Debug)]
-pub struct ListResponse {
-    pub result: Vec<RecordResponse>,
+pub struct ApiResponse<T> {
+    pub result: Option<T>,
 
+    #[serde(default)]
     pub errors: Vec<Message>,
+    #[serde(default)]
     pub messages: Vec<Message>,
 
     pub success: bool,
     pub result_info: Option<ResultInfo>,
 }
 
+pub type ListResponse = ApiResponse<Vec<RecordResponse>>;
+pub type PatchResponse = ApiResponse<RecordResponse>;
+
+/// A zone as listed by the API, only the fields needed to find it are read
+#[derive(Deserialize, Debug)]
+pub struct ZoneResponse {
+    pub id: MaxLenString<32>,
+    pub name: String,
+}
+
+/// Result of verifying an API token
 #[derive(Deserialize, Debug)]
-pub struct PatchResponse {
-    pub result: RecordResponse,
+pub struct TokenStatus {
+    pub id: String,
+    /// `active`, `disabled` or `expired`
+    pub status: String,
+}
 
-    pub errors: Vec<Message>,
-    pub messages: Vec<Message>,
+/// Body of a request creating a new record
+#[derive(Serialize, Debug)]
+pub struct RecordRequest {
+    #[serde(flatten)]
+    pub type_data: TypeSpecificData,
 
-    pub success: bool,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<TTLU32>,
+}
+
+/// Body of a request changing the given fields of an existing record
+#[derive(Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct RecordPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Typed data of records that do not have a plain content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxied: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<TTLU32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+}
+
+/// A patch of the record with the given id, as part of a batch
+#[derive(Serialize, Debug, Clone)]
+pub struct BatchPatch {
+    pub id: String,
+    #[serde(flatten)]
+    pub patch: RecordPatch,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BatchDelete {
+    pub id: String,
+}
+
+/// Body of a request applying several changes to the records of a zone at once.
+/// Either all changes are applied or none.
+#[derive(Serialize, Default, Debug)]
+pub struct BatchRequest {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deletes: Vec<BatchDelete>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<BatchPatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub posts: Vec<RecordRequest>,
+}
+impl BatchRequest {
+    pub fn len(&self) -> usize {
+        self.deletes.len() + self.patches.len() + self.posts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The records after a batch was applied
+#[derive(Deserialize, Default, Debug)]
+pub struct BatchResponse {
+    #[serde(default)]
+    pub deletes: Vec<RecordResponse>,
+    #[serde(default)]
+    pub patches: Vec<RecordResponse>,
+    #[serde(default)]
+    pub posts: Vec<RecordResponse>,
 }
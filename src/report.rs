@@ -0,0 +1,227 @@
+//! Results of the commands of the binary, printed as table or as JSON
+
+use std::{
+    fmt::{Display, Formatter},
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use serde::Serialize;
+
+use crate::records::RecordResponse;
+
+/// Writes rows as columns aligned to the widest cell, separated by two spaces
+pub(crate) fn write_table(
+    f: &mut Formatter<'_>,
+    header: &[&str],
+    rows: &[Vec<&str>],
+) -> std::fmt::Result {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let mut write_row = |cells: &[&str]| -> std::fmt::Result {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{c:<w$}"))
+            .collect();
+        writeln!(f, "{}", line.join("  ").trim_end())
+    };
+    write_row(header)?;
+    for row in rows {
+        write_row(row)?;
+    }
+    Ok(())
+}
+
+/// A record matched by a search rule
+#[derive(Serialize, Debug)]
+pub struct ListedRecord {
+    pub zone: String,
+    /// Index of the search rule in the zone
+    pub rule: usize,
+    pub id: String,
+    pub name: String,
+    pub r#type: &'static str,
+    pub content: String,
+    pub ttl: Option<u32>,
+    pub proxied: Option<bool>,
+}
+
+/// Records matched by the search rules of all zones
+#[derive(Serialize, Default, Debug)]
+pub struct RecordListing {
+    pub records: Vec<ListedRecord>,
+}
+impl RecordListing {
+    pub fn add(&mut self, zone: &str, rule: usize, record: &RecordResponse) {
+        self.records.push(ListedRecord {
+            zone: zone.to_owned(),
+            rule,
+            id: record.id.to_string(),
+            name: record.name.to_string(),
+            r#type: record.type_data.type_name(),
+            content: record
+                .type_data
+                .templated_value()
+                .unwrap_or(record.type_data.content())
+                .to_owned(),
+            ttl: record.ttl.map(|t| t.0),
+            proxied: record.type_data.proxied(),
+        });
+    }
+}
+impl Display for RecordListing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.records.is_empty() {
+            return writeln!(f, "No records");
+        }
+        let cells: Vec<[String; 3]> = self
+            .records
+            .iter()
+            .map(|r| {
+                [
+                    r.rule.to_string(),
+                    match r.ttl {
+                        Some(1) => "auto".to_owned(),
+                        Some(ttl) => ttl.to_string(),
+                        None => "-".to_owned(),
+                    },
+                    r.proxied.map_or("-".to_owned(), |p| p.to_string()),
+                ]
+            })
+            .collect();
+        let rows: Vec<Vec<&str>> = self
+            .records
+            .iter()
+            .zip(&cells)
+            .map(|(r, [rule, ttl, proxied])| {
+                vec![
+                    &*r.zone,
+                    rule,
+                    &*r.name,
+                    r.r#type,
+                    &*r.content,
+                    ttl,
+                    proxied,
+                ]
+            })
+            .collect();
+        write_table(
+            f,
+            &["ZONE", "RULE", "NAME", "TYPE", "CONTENT", "TTL", "PROXIED"],
+            &rows,
+        )
+    }
+}
+
+/// Addresses the records of a zone are set to
+#[derive(Serialize, Debug)]
+pub struct ZoneAddresses {
+    pub zone: String,
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// The detected addresses, and the addresses used for each zone if they differ by fallbacks
+#[derive(Serialize, Debug)]
+pub struct AddressReport {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<ZoneAddresses>,
+}
+impl Display for AddressReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let show = |a: Option<String>| a.unwrap_or_else(|| "(none)".to_owned());
+        let mut cells = vec![[
+            "(detected)".to_owned(),
+            show(self.ipv4.map(|a| a.to_string())),
+            show(self.ipv6.map(|a| a.to_string())),
+        ]];
+        cells.extend(self.zones.iter().map(|z| {
+            [
+                z.zone.clone(),
+                show(z.ipv4.map(|a| a.to_string())),
+                show(z.ipv6.map(|a| a.to_string())),
+            ]
+        }));
+        let rows: Vec<Vec<&str>> = cells
+            .iter()
+            .map(|row| row.iter().map(String::as_str).collect())
+            .collect();
+        write_table(f, &["ZONE", "IPV4", "IPV6"], &rows)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneStatus {
+    /// All records were changed as needed
+    Updated,
+    /// Skipped as the state file marks the zone as up to date with the addresses
+    UpToDate,
+    /// The records could not be listed, or some of them could not be changed
+    Failed,
+}
+impl Display for ZoneStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Updated => "updated",
+            Self::UpToDate => "up to date",
+            Self::Failed => "failed",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ZoneSummary {
+    pub zone: String,
+    pub status: ZoneStatus,
+    pub patched: u16,
+    pub created: u16,
+    pub deleted: u16,
+    /// Number of records that could not be changed
+    pub failed: usize,
+}
+
+/// What a run did to the records of every zone
+#[derive(Serialize, Default, Debug)]
+pub struct RunSummary {
+    pub zones: Vec<ZoneSummary>,
+}
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let cells: Vec<[String; 5]> = self
+            .zones
+            .iter()
+            .map(|z| {
+                [
+                    z.status.to_string(),
+                    z.patched.to_string(),
+                    z.created.to_string(),
+                    z.deleted.to_string(),
+                    z.failed.to_string(),
+                ]
+            })
+            .collect();
+        let rows: Vec<Vec<&str>> = self
+            .zones
+            .iter()
+            .zip(&cells)
+            .map(|(z, cells)| {
+                let mut row = vec![&*z.zone];
+                row.extend(cells.iter().map(String::as_str));
+                row
+            })
+            .collect();
+        write_table(
+            f,
+            &["ZONE", "STATUS", "PATCHED", "CREATED", "DELETED", "FAILED"],
+            &rows,
+        )
+    }
+}
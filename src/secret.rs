@@ -0,0 +1,99 @@
+use std::{
+    fmt::{Debug, Display, Formatter},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::error::Error;
+
+/// Where a secret is read from when the config is loaded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    /// Name of an environment variable
+    Env(String),
+    /// Path of a file containing only the secret
+    File(PathBuf),
+    /// Name of a credential passed by systemd, read from `$CREDENTIALS_DIRECTORY`
+    SystemdCredential(String),
+}
+
+impl Display for SecretSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "environment variable {name}"),
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::SystemdCredential(name) => write!(f, "systemd credential {name}"),
+        }
+    }
+}
+
+/// A secret such as an API token, either written into the config file or read from a [`SecretSource`].
+///
+/// The value is never shown in `Debug` output.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Secret {
+    Plain(String),
+    Source(SecretSource),
+}
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(_) => f.write_str("Secret(<redacted>)"),
+            Self::Source(source) => f.debug_tuple("Secret").field(source).finish(),
+        }
+    }
+}
+
+async fn read_secret_file(path: &Path, what: &str) -> Result<String, Error> {
+    let text = fs::read_to_string(path)
+        .await
+        .map_err(|e| Error::Config(format!("Could not read {what} {}: {e}", path.display())))?;
+    // Files written by editors or `echo` end with a newline that is not part of the secret
+    Ok(text.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+impl Secret {
+    /// The value of the secret, failing if it has not been [resolved](Self::resolve)
+    pub fn expose(&self) -> Result<&str, Error> {
+        match self {
+            Self::Plain(value) => Ok(value),
+            Self::Source(source) => Err(Error::Config(format!(
+                "Secret from {source} has not been read yet"
+            ))),
+        }
+    }
+
+    /// Replaces a value written into the config with `<redacted>`, keeping references to sources
+    pub fn redact(&mut self) {
+        if let Self::Plain(value) = self {
+            *value = "<redacted>".to_owned();
+        }
+    }
+
+    /// Reads the secret from its source, replacing the source with the value
+    pub async fn resolve(&mut self) -> Result<(), Error> {
+        let value = match self {
+            Self::Plain(_) => return Ok(()),
+            Self::Source(SecretSource::Env(name)) => std::env::var(&*name).map_err(|e| {
+                Error::Config(format!(
+                    "Could not read secret from environment variable {name}: {e}"
+                ))
+            })?,
+            Self::Source(SecretSource::File(path)) => read_secret_file(path, "secret file").await?,
+            Self::Source(SecretSource::SystemdCredential(name)) => {
+                let dir = std::env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+                    Error::Config(format!(
+                        "Could not read systemd credential {name}: CREDENTIALS_DIRECTORY is not set, is the service started with LoadCredential?"
+                    ))
+                })?;
+                read_secret_file(&Path::new(&dir).join(&*name), "systemd credential").await?
+            }
+        };
+        *self = Self::Plain(value);
+        Ok(())
+    }
+}
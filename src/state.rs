@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::{
+    config::{FailedFamilies, Zone},
+    error::Error,
+    records::RecordResponse,
+};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct RecordState {
+    pub name: String,
+    pub content: String,
+}
+impl From<&RecordResponse> for RecordState {
+    fn from(record: &RecordResponse) -> Self {
+        Self {
+            name: record.name.to_string(),
+            content: record.type_data.content().to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct ZoneState {
+    /// Records that are up to date with the stored addresses, keyed by record id
+    pub records: HashMap<String, RecordState>,
+    /// [`config_hash`](Self::config_hash) of the zone when it was updated
+    #[serde(default)]
+    pub config_hash: u64,
+}
+impl ZoneState {
+    /// Hash of the effective config of the zone, so that a zone is updated again when its config changes.
+    /// It is the 64 bit FNV-1a hash of the zone serialized to JSON, which is stable across builds.
+    /// The credentials and the id of the zone are left out, as changing them does not change any record.
+    pub fn config_hash(zone: &Zone) -> u64 {
+        let mut value = serde_json::to_value(zone).expect("Zone should be serializable");
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("auth");
+            fields.remove("identifier");
+        }
+        value
+            .to_string()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    /// Whether the zone was updated with the same config
+    pub fn is_up_to_date(&self, zone: &Zone) -> bool {
+        self.config_hash == Self::config_hash(zone)
+    }
+}
+
+/// Last known addresses and the zones that have been fully updated to them
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct State {
+    pub addresses: Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)>,
+    pub zones: HashMap<String, ZoneState>,
+    /// Ids of the zones configured by name, keyed by zone name
+    #[serde(default)]
+    pub zone_ids: HashMap<String, String>,
+}
+impl State {
+    /// The detected addresses, with the last known address for each family whose detection failed
+    pub fn known_addresses(
+        &self,
+        addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+        failed: FailedFamilies,
+    ) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        let known = self.addresses.unwrap_or_default();
+        (
+            if failed.ipv4 { known.0 } else { addresses.0 },
+            if failed.ipv6 { known.1 } else { addresses.1 },
+        )
+    }
+
+    /// Whether every zone was updated to these addresses with its current config, so that patching
+    /// would not change anything. Zones configured by name are found by their remembered id.
+    pub fn is_up_to_date(
+        &self,
+        zones: &[Zone],
+        addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>),
+        failed: FailedFamilies,
+    ) -> bool {
+        if self.addresses != Some(self.known_addresses(addresses, failed)) {
+            return false;
+        }
+        zones.iter().all(|zone| {
+            let id = match (&zone.identifier, &zone.name) {
+                (Some(id), _) => Some(&id.0),
+                (None, Some(name)) => self.zone_ids.get(name),
+                (None, None) => None,
+            };
+            id.and_then(|id| self.zones.get(id))
+                .is_some_and(|z| z.is_up_to_date(zone))
+        })
+    }
+
+    /// Sets the current addresses, forgetting all zones if they differ from the stored ones
+    pub fn update_addresses(&mut self, addresses: (Option<Ipv4Addr>, Option<Ipv6Addr>)) {
+        if self.addresses != Some(addresses) {
+            self.addresses = Some(addresses);
+            self.zones.clear();
+        }
+    }
+
+    /// Loads the state file, starting with an empty state if it does not exist or is unreadable
+    pub async fn load(path: &Path) -> Self {
+        let text = match fs::read_to_string(path).await {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("No state file found at {}", path.display());
+                return Self::default();
+            }
+            Err(e) => {
+                log::warn!("Could not read state file, ignoring it: {e}");
+                return Self::default();
+            }
+        };
+        match serde_json::from_str(&text) {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("Could not parse state file, ignoring it: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    /// Writes the state to a temporary file and renames it over the state file,
+    /// so the state file is never left partially written
+    pub async fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path: PathBuf = path.with_file_name(tmp_name);
+
+        let json = serde_json::to_vec_pretty(self).expect("State should be serializable");
+        let mut file = fs::File::create(&tmp_path)
+            .await
+            .map_err(|source| Error::Io {
+                context: "Could not create temporary state file",
+                source,
+            })?;
+        file.write_all(&json).await.map_err(|source| Error::Io {
+            context: "Could not write temporary state file",
+            source,
+        })?;
+        file.sync_all().await.map_err(|source| Error::Io {
+            context: "Could not sync temporary state file",
+            source,
+        })?;
+        drop(file);
+
+        fs::rename(&tmp_path, path)
+            .await
+            .map_err(|source| Error::Io {
+                context: "Could not replace state file",
+                source,
+            })?;
+        Ok(())
+    }
+}
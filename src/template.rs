@@ -0,0 +1,220 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use crate::plan::SkipReason;
+
+/// Values templates are rendered with
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateValues<'a> {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+    /// Host name of the machine, see [`hostname`]
+    pub hostname: Option<&'a str>,
+    /// Full name of the record the template is rendered for
+    pub name: &'a str,
+    /// Time of the update, rendered as RFC 3339 timestamp
+    pub now: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Ipv4,
+    Ipv6,
+    Hostname,
+    Name,
+    Timestamp,
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Placeholder(Placeholder),
+}
+
+/// Splits a template into text and placeholders. Braces are written as `{{` and `}}`.
+fn parse(template: &str) -> Result<Vec<Piece<'_>>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        if i > 0 {
+            pieces.push(Piece::Text(&rest[..i]));
+        }
+        let (brace, after) = rest[i..].split_at(1);
+        if after.starts_with(brace) {
+            pieces.push(Piece::Text(brace));
+            rest = &after[1..];
+            continue;
+        }
+        if brace == "}" {
+            return Err("Unmatched \"}\", write \"}}\" for a literal brace".to_owned());
+        }
+        let end = after
+            .find('}')
+            .ok_or_else(|| "Unclosed \"{\", write \"{{\" for a literal brace".to_owned())?;
+        let placeholder = match &after[..end] {
+            "ipv4" => Placeholder::Ipv4,
+            "ipv6" => Placeholder::Ipv6,
+            "hostname" => Placeholder::Hostname,
+            "name" => Placeholder::Name,
+            "timestamp" => Placeholder::Timestamp,
+            p => {
+                return Err(format!(
+                    "Unknown placeholder \"{{{p}}}\", expected one of {{ipv4}}, {{ipv6}}, {{hostname}}, {{name}} or {{timestamp}}"
+                ))
+            }
+        };
+        pieces.push(Piece::Placeholder(placeholder));
+        rest = &after[end + 1..];
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest));
+    }
+    Ok(pieces)
+}
+
+/// Checks that the template only uses known placeholders
+pub fn validate(template: &str) -> Result<(), String> {
+    parse(template).map(|_| ())
+}
+
+/// Renders the parts of the template between the timestamps, failing if a value used by it is not available
+fn render_segments(template: &str, values: &TemplateValues) -> Result<Vec<String>, SkipReason> {
+    let pieces = parse(template).map_err(|_| SkipReason::InvalidTemplate)?;
+    let mut segments = Vec::new();
+    let mut rendered = String::with_capacity(template.len());
+    for piece in pieces {
+        match piece {
+            Piece::Text(t) => rendered.push_str(t),
+            Piece::Placeholder(Placeholder::Timestamp) => {
+                segments.push(std::mem::take(&mut rendered))
+            }
+            Piece::Placeholder(Placeholder::Ipv4) => {
+                let v4 = values.ipv4.ok_or(SkipReason::NoIpv4Address)?;
+                rendered.push_str(&v4.to_string());
+            }
+            Piece::Placeholder(Placeholder::Ipv6) => {
+                let v6 = values.ipv6.ok_or(SkipReason::NoIpv6Address)?;
+                rendered.push_str(&v6.to_string());
+            }
+            Piece::Placeholder(Placeholder::Hostname) => {
+                rendered.push_str(values.hostname.ok_or(SkipReason::NoHostname)?)
+            }
+            Piece::Placeholder(Placeholder::Name) => rendered.push_str(values.name),
+        }
+    }
+    segments.push(rendered);
+    Ok(segments)
+}
+
+/// Renders the template, failing if a value used by it is not available
+pub fn render(template: &str, values: &TemplateValues) -> Result<String, SkipReason> {
+    let timestamp = humantime::format_rfc3339_seconds(values.now).to_string();
+    Ok(render_segments(template, values)?.join(&timestamp))
+}
+
+/// Whether `current` is the template rendered at any time, so that records are not changed
+/// only to update a timestamp
+pub fn matches(template: &str, values: &TemplateValues, current: &str) -> Result<bool, SkipReason> {
+    let segments = render_segments(template, values)?;
+    let Some(mut rest) = current.strip_prefix(segments[0].as_str()) else {
+        return Ok(false);
+    };
+    let Some((last, middle)) = segments[1..].split_last() else {
+        return Ok(rest.is_empty());
+    };
+    for segment in middle {
+        match rest.find(segment.as_str()) {
+            Some(i) => rest = &rest[i + segment.len()..],
+            None => return Ok(false),
+        }
+    }
+    Ok(rest.ends_with(last.as_str()))
+}
+
+#[cfg(unix)]
+fn read_hostname() -> Option<String> {
+    nix::unistd::gethostname()
+        .inspect_err(|e| log::warn!("Could not get host name: {e}"))
+        .ok()?
+        .into_string()
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn read_hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Host name of the machine, read once and remembered
+pub fn hostname() -> Option<&'static str> {
+    static HOSTNAME: OnceLock<Option<String>> = OnceLock::new();
+    HOSTNAME.get_or_init(read_hostname).as_deref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> TemplateValues<'static> {
+        TemplateValues {
+            ipv4: Some(Ipv4Addr::new(198, 51, 100, 7)),
+            ipv6: None,
+            hostname: Some("router"),
+            name: "host.example.com",
+            now: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn renders_placeholders_and_escaped_braces() {
+        assert_eq!(
+            render("v=spf1 ip4:{ipv4} -all {{{hostname}}} {name}", &values()),
+            Ok("v=spf1 ip4:198.51.100.7 -all {router} host.example.com".to_owned())
+        );
+    }
+
+    #[test]
+    fn missing_value_skips() {
+        assert_eq!(
+            render("ip6:{ipv6}", &values()),
+            Err(SkipReason::NoIpv6Address)
+        );
+    }
+
+    #[test]
+    fn timestamps_match_any_time() {
+        let template = "updated by {hostname} at {timestamp}";
+        assert_eq!(
+            render(template, &values()),
+            Ok("updated by router at 1970-01-01T00:00:00Z".to_owned())
+        );
+        assert_eq!(
+            matches(
+                template,
+                &values(),
+                "updated by router at 2024-05-01T12:00:00Z"
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            matches(
+                template,
+                &values(),
+                "updated by nas at 2024-05-01T12:00:00Z"
+            ),
+            Ok(false)
+        );
+        assert_eq!(matches("{ipv4}", &values(), "198.51.100.7"), Ok(true));
+        assert_eq!(matches("{ipv4}", &values(), "198.51.100.70"), Ok(false));
+    }
+
+    #[test]
+    fn rejects_unknown_and_unbalanced_placeholders() {
+        assert!(validate("{ipv5}").is_err());
+        assert!(validate("{ipv4").is_err());
+        assert!(validate("ipv4}").is_err());
+        assert!(validate("{{ipv4}}").is_ok());
+    }
+}
@@ -0,0 +1,1767 @@
+mod common;
+
+use std::sync::Arc;
+
+use cloudflare_dyndns::{
+    api::{
+        get_ip_addresses, list_records, list_zone, patch_zone, patch_zones, plan_zone,
+        resolve_zones, verify_credentials, verify_credentials_for_run,
+    },
+    client::Clients,
+    config::{Config, FailedFamilies, Ipv6Rewrite, SearchRule, Zone},
+    error::Error,
+    plan::{ChangeKind, Plan, SkipReason},
+    report::{RecordListing, RunSummary, ZoneStatus},
+    state::{State, ZoneState},
+};
+use common::*;
+use serde_json::json;
+use wiremock::{
+    matchers::{body_json, body_partial_json, header, method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+#[test]
+fn search_rule_serializes_to_url_params() {
+    let rule: SearchRule = toml::from_str(
+        r#"
+        "comment.contains" = "ddns"
+        "match" = "all"
+        name = "a.example.com"
+        per_page = 100
+        type = "AAAA"
+        ipv6_suffix = "::10"
+        ipv6_prefix_length = 56
+        "#,
+    )
+    .unwrap();
+
+    // The IPv6 rewrite is not a filter and must not be sent
+    assert_eq!(
+        serde_url_params::to_string(&rule.query()).unwrap(),
+        "comment.contains=ddns&match=all&name=a.example.com&per_page=100&type=AAAA"
+    );
+    assert_eq!(
+        serde_url_params::to_string(&SearchRule::default().query()).unwrap(),
+        ""
+    );
+}
+
+#[test]
+fn sort_and_match_options_are_plain_params() {
+    let rule: SearchRule = toml::from_str(
+        r#"
+        direction = "desc"
+        order = "name"
+        "match" = "any"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        serde_url_params::to_string(&rule.query()).unwrap(),
+        "direction=desc&match=any&order=name"
+    );
+}
+
+#[tokio::test]
+async fn list_records_merges_results_of_all_rules() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "A"))
+        .and(header("Authorization", "Bearer secret-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![
+                record("1", "a.example.com", "A", "192.0.2.1"),
+                record("2", "b.example.com", "A", "192.0.2.1"),
+            ],
+            1,
+            1,
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", "a.example.com"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![
+                record("1", "a.example.com", "A", "192.0.2.1"),
+                record("3", "a.example.com", "AAAA", "2001:db8::1"),
+            ],
+            1,
+            1,
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        [[search]]
+        type = "A"
+        [[search]]
+        name = "a.example.com"
+        "#,
+    );
+    let records = list_records(&zone, &client(&server)).await.unwrap();
+
+    let mut ids: Vec<_> = records.keys().map(String::as_str).collect();
+    ids.sort();
+    assert_eq!(ids, ["1", "2", "3"]);
+}
+
+#[tokio::test]
+async fn list_records_follows_pages() {
+    let server = MockServer::start().await;
+    for page in 1..=3 {
+        Mock::given(method("GET"))
+            .and(path(RECORDS_PATH))
+            .and(query_param("page", page.to_string()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+                vec![record(&page.to_string(), "a.example.com", "A", "192.0.2.1")],
+                page,
+                3,
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let zone = zone(&server, "[[search]]\ntype = \"A\"");
+    let records = list_records(&zone, &client(&server)).await.unwrap();
+    assert_eq!(records.len(), 3);
+}
+
+#[tokio::test]
+async fn list_records_only_fetches_pinned_page() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("page", "2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("2", "a.example.com", "A", "192.0.2.1")],
+            2,
+            3,
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(&server, "[[search]]\npage = 2");
+    let records = list_records(&zone, &client(&server)).await.unwrap();
+    assert_eq!(records.len(), 1);
+}
+
+#[tokio::test]
+async fn list_records_fails_on_error_status() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(
+            ResponseTemplate::new(403).set_body_json(error_body(10000, "Authentication error")),
+        )
+        .mount(&server)
+        .await;
+
+    let zone = zone(&server, "[[search]]\ntype = \"A\"");
+    let e = list_records(&zone, &client(&server)).await.unwrap_err();
+    assert!(matches!(e, Error::Auth { .. }), "{e}");
+    assert!(e.to_string().contains("Authentication error"), "{e}");
+}
+
+#[tokio::test]
+async fn list_records_fails_if_any_rule_fails() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "A"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", "192.0.2.1")],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "AAAA"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    // Patching only the records of the first rule would let the state mark the zone as up to date
+    let zone = zone(
+        &server,
+        "[[search]]\ntype = \"A\"\n[[search]]\ntype = \"AAAA\"",
+    );
+    list_records(&zone, &client(&server)).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn list_records_accepts_rules_without_records() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(vec![], 1, 1)))
+        .mount(&server)
+        .await;
+
+    let zone = zone(&server, "[[search]]\ntype = \"A\"");
+    let records = list_records(&zone, &client(&server)).await.unwrap();
+    assert!(records.is_empty());
+}
+
+#[tokio::test]
+async fn patch_zone_only_patches_changed_records() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![
+                record("1", "a.example.com", "A", "192.0.2.1"),
+                record("2", "a.example.com", "AAAA", &V6.to_string()),
+                record("3", "a.example.com", "TXT", "hello"),
+            ],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(header("Authorization", "Bearer secret-token"))
+        .and(body_json(json!({ "content": V4.to_string() })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            &V4.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/2")))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let zone = zone(&server, "[[search]]\nname = \"a.example.com\"");
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), Some(V6)),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.patched, 1);
+    assert!(result.errors.is_empty());
+    assert_eq!(result.records["1"].content, V4.to_string());
+    assert_eq!(result.records["2"].content, V6.to_string());
+    assert!(!result.records.contains_key("3"));
+}
+
+#[tokio::test]
+async fn patch_zone_counts_failed_patches() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", "192.0.2.1")],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(500).set_body_string("Internal error"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(&server, "[[search]]\ntype = \"A\"");
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.patched, 0);
+    assert!(matches!(
+        result.errors[..],
+        [Error::ApiError { code, .. }] if code.as_u16() == 500
+    ));
+    assert!(result.records.is_empty());
+}
+
+#[tokio::test]
+async fn patch_zones_skips_zones_up_to_date_in_state() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", &V4.to_string())],
+            1,
+            1,
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut zones = [zone(&server, "[[search]]\ntype = \"A\"")];
+    let mut state = State::default();
+    let mut summary = RunSummary::default();
+    let clients = Clients::new(context(), &zones);
+    patch_zones(
+        &mut zones,
+        &clients,
+        (Some(V4), None),
+        FailedFamilies::default(),
+        &mut state,
+        &mut summary,
+    )
+    .await
+    .unwrap();
+    assert!(state.zones.contains_key(ZONE_ID));
+
+    // The second run must not list the records again
+    patch_zones(
+        &mut zones,
+        &clients,
+        (Some(V4), None),
+        FailedFamilies::default(),
+        &mut state,
+        &mut summary,
+    )
+    .await
+    .unwrap();
+    let statuses: Vec<ZoneStatus> = summary.zones.iter().map(|z| z.status).collect();
+    assert_eq!(statuses, [ZoneStatus::Updated, ZoneStatus::UpToDate]);
+}
+
+#[tokio::test]
+async fn patch_zones_updates_zones_whose_config_changed() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", &V4.to_string())],
+            1,
+            1,
+        )))
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_json(json!({ "ttl": 300 })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            &V4.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut state = State::default();
+    let mut summary = RunSummary::default();
+    for extra in ["", "set = { ttl = 300 }"] {
+        let mut zones = [zone(&server, &format!("[[search]]\ntype = \"A\"\n{extra}"))];
+        let clients = Clients::new(context(), &zones);
+        patch_zones(
+            &mut zones,
+            &clients,
+            (Some(V4), None),
+            FailedFamilies::default(),
+            &mut state,
+            &mut summary,
+        )
+        .await
+        .unwrap();
+    }
+    let statuses: Vec<ZoneStatus> = summary.zones.iter().map(|z| z.status).collect();
+    assert_eq!(statuses, [ZoneStatus::Updated, ZoneStatus::Updated]);
+}
+
+#[tokio::test]
+async fn patch_zones_updates_zones_whose_config_changed_since_the_state_was_saved() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", &V4.to_string())],
+            1,
+            1,
+        )))
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_json(json!({ "ttl": 300 })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            &V4.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir =
+        std::env::temp_dir().join(format!("cloudflare_dyndns-repatch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let state_file = dir.join("state.json");
+    let _ = std::fs::remove_file(&state_file);
+
+    let mut summary = RunSummary::default();
+    for extra in ["", "", "set = { ttl = 300 }"] {
+        let mut zones = [zone(&server, &format!("[[search]]\ntype = \"A\"\n{extra}"))];
+        let mut state = State::load(&state_file).await;
+        let clients = Clients::new(context(), &zones);
+        patch_zones(
+            &mut zones,
+            &clients,
+            (Some(V4), None),
+            FailedFamilies::default(),
+            &mut state,
+            &mut summary,
+        )
+        .await
+        .unwrap();
+        state.save(&state_file).await.unwrap();
+    }
+    let statuses: Vec<ZoneStatus> = summary.zones.iter().map(|z| z.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            ZoneStatus::Updated,
+            ZoneStatus::UpToDate,
+            ZoneStatus::Updated
+        ]
+    );
+}
+
+#[tokio::test]
+async fn patch_zones_returns_zone_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "30"))
+        .mount(&server)
+        .await;
+
+    let mut zones = [zone(&server, "[[search]]\ntype = \"A\"")];
+    let mut state = State::default();
+    let mut summary = RunSummary::default();
+    let clients = Clients::new(context(), &zones);
+    let e = patch_zones(
+        &mut zones,
+        &clients,
+        (Some(V4), None),
+        FailedFamilies::default(),
+        &mut state,
+        &mut summary,
+    )
+    .await
+    .unwrap_err();
+
+    assert!(matches!(
+        e,
+        Error::RateLimited { retry_after: Some(d) } if d.as_secs() == 30
+    ));
+    assert!(state.zones.is_empty());
+    assert_eq!(summary.zones[0].status, ZoneStatus::Failed);
+}
+
+#[tokio::test]
+async fn list_zone_lists_records_of_each_rule() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "A"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", &V4.to_string())],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "AAAA"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![
+                record("1", "a.example.com", "A", &V4.to_string()),
+                record("2", "a.example.com", "AAAA", &V6.to_string()),
+            ],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        "[[search]]\ntype = \"A\"\n[[search]]\ntype = \"AAAA\"",
+    );
+    let mut listing = RecordListing::default();
+    list_zone(&zone, &client(&server), &mut listing)
+        .await
+        .unwrap();
+
+    // Records matched by several rules are listed for each of them
+    let listed: Vec<(usize, &str)> = listing.records.iter().map(|r| (r.rule, &*r.id)).collect();
+    assert_eq!(listed, [(0, "1"), (1, "1"), (1, "2")]);
+    assert!(listing.to_string().contains("2001:db8::7"));
+}
+
+fn ip_config(server: &MockServer, extra: &str) -> Config {
+    toml::from_str(&format!(
+        r#"
+        ipv4_service = "{0}/ip4"
+        zones = []
+        {extra}
+        "#,
+        server.uri()
+    ))
+    .unwrap()
+}
+
+async fn mount_echo(server: &MockServer, route: &str, body: &str) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(ResponseTemplate::new(200).set_body_string(body))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn get_ip_addresses_from_echo_services() {
+    let server = MockServer::start().await;
+    mount_echo(&server, "/ip4", "198.51.100.7\n").await;
+    mount_echo(&server, "/ip6/a", "2001:db8::7").await;
+    mount_echo(&server, "/ip6/b", "2001:db8::7").await;
+    mount_echo(&server, "/ip6/c", "2001:db8::8").await;
+
+    let conf = ip_config(
+        &server,
+        &format!(
+            r#"
+            [ipv6_service]
+            services = ["{0}/ip6/a", "{0}/ip6/b", "{0}/ip6/c"]
+            strategy = "quorum"
+            "#,
+            server.uri()
+        ),
+    );
+    let addresses = get_ip_addresses(&conf, &reqwest::Client::new())
+        .await
+        .unwrap()
+        .addresses();
+    assert_eq!(addresses, (Some(V4), Some(V6)));
+}
+
+#[tokio::test]
+async fn get_ip_addresses_breaks_ties_by_service_order() {
+    let server = MockServer::start().await;
+    mount_echo(&server, "/ip4", "198.51.100.7").await;
+    mount_echo(&server, "/ip6/a", "2001:db8::7").await;
+    mount_echo(&server, "/ip6/b", "2001:db8::8").await;
+
+    let conf = ip_config(
+        &server,
+        &format!(
+            r#"
+            [ipv6_service]
+            services = ["{0}/ip6/b", "{0}/ip6/a", "{0}/ip6/a", "{0}/ip6/b"]
+            strategy = "quorum"
+            quorum = 2
+            "#,
+            server.uri()
+        ),
+    );
+    let addresses = get_ip_addresses(&conf, &reqwest::Client::new())
+        .await
+        .unwrap()
+        .addresses();
+    assert_eq!(addresses.1, Some("2001:db8::8".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn get_ip_addresses_falls_back_to_next_service() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/broken"))
+        .respond_with(ResponseTemplate::new(502))
+        .mount(&server)
+        .await;
+    mount_echo(&server, "/ip6", "2001:db8::7").await;
+
+    let conf = ip_config(
+        &server,
+        &format!(
+            r#"
+            [ipv6_service]
+            services = ["{0}/broken", "{0}/ip6"]
+            "#,
+            server.uri()
+        ),
+    );
+    // The IPv4 service is not mounted and returns 404
+    let addresses = get_ip_addresses(&conf, &reqwest::Client::new())
+        .await
+        .unwrap()
+        .addresses();
+    assert_eq!(addresses, (None, Some(V6)));
+}
+
+#[tokio::test]
+async fn get_ip_addresses_requires_agreement() {
+    let server = MockServer::start().await;
+    mount_echo(&server, "/ip4", "198.51.100.7").await;
+    mount_echo(&server, "/ip6/a", "2001:db8::7").await;
+    mount_echo(&server, "/ip6/b", "2001:db8::8").await;
+
+    let conf = ip_config(
+        &server,
+        &format!(
+            r#"
+            [ipv6_service]
+            services = ["{0}/ip6/a", "{0}/ip6/b"]
+            strategy = "all_agree"
+            "#,
+            server.uri()
+        ),
+    );
+    let addresses = get_ip_addresses(&conf, &reqwest::Client::new())
+        .await
+        .unwrap()
+        .addresses();
+    assert_eq!(addresses, (Some(V4), None));
+}
+
+#[tokio::test]
+async fn get_ip_addresses_fails_without_any_address() {
+    let server = MockServer::start().await;
+    mount_echo(&server, "/ip4", "not an address").await;
+
+    let conf = ip_config(&server, "");
+    let e = get_ip_addresses(&conf, &reqwest::Client::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(e, Error::IpDetection(_)), "{e}");
+    assert_eq!(e.exit_code(), 5);
+}
+
+#[tokio::test]
+async fn patch_zone_creates_missing_ensured_records() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", "a.example.com"))
+        .and(query_param("type", "A"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", "192.0.2.1")],
+            1,
+            1,
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", "new.example.com"))
+        .and(query_param("type", "AAAA"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(vec![], 1, 1)))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            &V4.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_json(json!({
+            "type": "AAAA",
+            "content": V6.to_string(),
+            "proxied": true,
+            "name": "new.example.com",
+            "comment": "managed by dyndns",
+            "tags": ["ddns"],
+            "ttl": 300,
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "2",
+            "new.example.com",
+            "AAAA",
+            &V6.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        [[ensure]]
+        name = "a.example.com"
+        type = "A"
+
+        [[ensure]]
+        name = "new.example.com"
+        type = "AAAA"
+        ttl = 300
+        proxied = true
+        comment = "managed by dyndns"
+        tags = ["ddns"]
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), Some(V6)),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.patched, 1);
+    assert_eq!(result.created, 1);
+    assert!(result.errors.is_empty());
+    assert_eq!(result.records["2"].content, V6.to_string());
+}
+
+async fn mount_listing(server: &MockServer, name: &str, records: Vec<serde_json::Value>) {
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", name))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(records, 1, 1)))
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+/// Plans the changes of a zone with one record to patch, delete, create, change the TTL of and skip
+async fn plan_example_zone() -> Plan {
+    let server = MockServer::start().await;
+    let a = "a.example.com";
+    mount_listing(
+        &server,
+        a,
+        vec![
+            record("1", a, "A", "192.0.2.1"),
+            record("2", a, "AAAA", &V6.to_string()),
+        ],
+    )
+    .await;
+    let b = record("3", "b.example.com", "A", &V4.to_string());
+    mount_listing(&server, "b.example.com", vec![b]).await;
+    let d = record("4", "d.example.com", "A", &V4.to_string());
+    mount_listing(&server, "d.example.com", vec![d]).await;
+    mount_listing(&server, "c.example.com", vec![]).await;
+    for verb in ["PATCH", "POST", "PUT", "DELETE"] {
+        Mock::given(method(verb))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+    }
+
+    let zone = zone(
+        &server,
+        r#"
+        name = "example.com"
+        missing_family = "delete"
+
+        [[search]]
+        name = "a.example.com"
+        [[search]]
+        name = "b.example.com"
+        set = { ttl = 300 }
+        [[search]]
+        name = "d.example.com"
+
+        [[ensure]]
+        name = "c.example.com"
+        type = "A"
+        "#,
+    );
+    let mut plan = Plan::default();
+    plan_zone(
+        &zone,
+        &client(&server),
+        (Some(V4), None),
+        FailedFamilies::default(),
+        &mut plan,
+    )
+    .await
+    .unwrap();
+    plan.sort();
+    plan
+}
+
+#[tokio::test]
+async fn plan_zone_does_not_change_records() {
+    let plan = plan_example_zone().await;
+
+    let changes: Vec<_> = plan
+        .changes
+        .iter()
+        .map(|c| (c.action, c.id.as_deref(), c.new.as_deref()))
+        .collect();
+    let v4 = V4.to_string();
+    assert_eq!(
+        changes,
+        [
+            (ChangeKind::Patch, Some("1"), Some(&*v4)),
+            (ChangeKind::Delete, Some("2"), None),
+            (ChangeKind::Patch, Some("3"), Some(&*v4)),
+            (ChangeKind::Create, None, Some(&*v4)),
+        ]
+    );
+    assert_eq!(plan.changes[2].fields.ttl.map(|t| t.0), Some(300));
+    assert_eq!(plan.skipped.len(), 1);
+    assert_eq!(plan.skipped[0].id.as_deref(), Some("4"));
+    assert_eq!(plan.skipped[0].reason, SkipReason::Unchanged);
+}
+
+#[tokio::test]
+async fn plan_is_printed_as_table_and_json() {
+    let plan = plan_example_zone().await;
+
+    assert_eq!(
+        plan.to_string(),
+        "\
+Changes:
+ZONE         NAME           TYPE  CURRENT       NEW
+example.com  a.example.com  A     192.0.2.1     198.51.100.7
+example.com  a.example.com  AAAA  2001:db8::7   (deleted)
+example.com  b.example.com  A     198.51.100.7  198.51.100.7 (and ttl)
+example.com  c.example.com  A     (new record)  198.51.100.7
+
+Skipped:
+ZONE         NAME           TYPE  CONTENT       REASON
+example.com  d.example.com  A     198.51.100.7  Content has not changed
+"
+    );
+
+    let json = serde_json::to_value(&plan).unwrap();
+    assert_eq!(
+        json["changes"][1],
+        json!({
+            "action": "delete",
+            "zone": "example.com",
+            "id": "2",
+            "name": "a.example.com",
+            "type": "AAAA",
+            "current": V6.to_string(),
+            "new": null,
+        })
+    );
+    // Fields besides the content are only included if they change
+    assert_eq!(json["changes"][2]["fields"], json!({ "ttl": 300 }));
+    assert!(json["changes"][0].get("fields").is_none());
+    assert_eq!(
+        json["changes"][3],
+        json!({
+            "action": "create",
+            "zone": "example.com",
+            "id": null,
+            "name": "c.example.com",
+            "type": "A",
+            "current": null,
+            "new": V4.to_string(),
+        })
+    );
+    assert_eq!(
+        json["skipped"],
+        json!([{
+            "zone": "example.com",
+            "id": "4",
+            "name": "d.example.com",
+            "type": "A",
+            "content": V4.to_string(),
+            "reason": "unchanged",
+        }])
+    );
+}
+
+#[tokio::test]
+async fn patch_zone_does_not_create_records_without_address() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(vec![], 1, 1)))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        "[[ensure]]\nname = \"new.example.com\"\ntype = \"AAAA\"",
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.created, 0);
+    assert!(result.errors.is_empty());
+}
+
+async fn mount_dual_stack_records(server: &MockServer) {
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![
+                record("1", "a.example.com", "A", &V4.to_string()),
+                record("2", "a.example.com", "AAAA", "2001:db8::1"),
+            ],
+            1,
+            1,
+        )))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn patch_zone_deletes_records_of_missing_family() {
+    let server = MockServer::start().await;
+    mount_dual_stack_records(&server).await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("{RECORDS_PATH}/2")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({ "id": "2" }))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        "missing_family = \"delete\"\n[[search]]\nname = \"a.example.com\"",
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.deleted, 1);
+    assert!(result.records.contains_key("1"));
+    assert!(!result.records.contains_key("2"));
+}
+
+#[tokio::test]
+async fn patch_zones_keeps_records_of_family_whose_detection_failed() {
+    let server = MockServer::start().await;
+    mount_echo(&server, "/ip4", &V4.to_string()).await;
+    Mock::given(method("GET"))
+        .and(path("/ip6"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    mount_dual_stack_records(&server).await;
+    Mock::given(method("DELETE"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({ "id": "2" }))))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let conf = ip_config(&server, &format!("ipv6_service = \"{}/ip6\"", server.uri()));
+    let detected = get_ip_addresses(&conf, &reqwest::Client::new())
+        .await
+        .unwrap();
+    assert!(detected.failed().ipv6);
+
+    let mut zones = [zone(
+        &server,
+        "missing_family = \"delete\"\n[[search]]\nname = \"a.example.com\"",
+    )];
+    let mut state = State::default();
+    let clients = Clients::new(context(), &zones);
+    patch_zones(
+        &mut zones,
+        &clients,
+        detected.addresses(),
+        detected.failed(),
+        &mut state,
+        &mut RunSummary::default(),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn patch_zone_replaces_missing_family_with_fallback() {
+    let server = MockServer::start().await;
+    mount_dual_stack_records(&server).await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/2")))
+        .and(body_json(json!({ "content": "2001:db8::ffff" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "2",
+            "a.example.com",
+            "AAAA",
+            "2001:db8::ffff",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        missing_family = "replace"
+        ipv6_fallback = "2001:db8::ffff"
+        [[search]]
+        name = "a.example.com"
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.patched, 1);
+    assert_eq!(result.records["2"].content, "2001:db8::ffff");
+}
+
+#[test]
+fn replace_policy_requires_fallback() {
+    let conf: Config = toml::from_str(
+        r#"
+        ipv4_service = "https://api.ipify.org"
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+        auth = { BearerAuth = "secret-token" }
+        missing_family = "replace"
+        "#,
+    )
+    .unwrap();
+    assert!(matches!(conf.validate(), Err(Error::Config(_))));
+}
+
+#[tokio::test]
+async fn resolve_zones_looks_up_names_once() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/zones"))
+        .and(query_param("name", "example.com"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!([
+            { "id": ZONE_ID, "name": "example.com", "status": "active" }
+        ]))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let named_zone = || -> Zone {
+        toml::from_str(&format!(
+            r#"
+            name = "example.com"
+            api_url = "{}"
+            auth = {{ BearerAuth = "secret-token" }}
+            "#,
+            server.uri()
+        ))
+        .unwrap()
+    };
+    let mut state = State::default();
+
+    let mut zones = [named_zone()];
+    let clients = Clients::new(context(), &zones);
+    resolve_zones(&mut zones, &clients, &mut state)
+        .await
+        .unwrap();
+    assert_eq!(zones[0].id(), ZONE_ID);
+    assert_eq!(zones[0].display_name(), "example.com");
+    assert_eq!(state.zone_ids["example.com"], ZONE_ID);
+
+    // The second lookup is served from the state
+    let mut zones = [named_zone()];
+    let clients = Clients::new(context(), &zones);
+    resolve_zones(&mut zones, &clients, &mut state)
+        .await
+        .unwrap();
+    assert_eq!(zones[0].id(), ZONE_ID);
+}
+
+#[tokio::test]
+async fn patch_zones_looks_up_ids_of_recreated_zones_again() {
+    const OLD_ID: &str = "023e105f4ecef8ad9ca31a8372d0c000";
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/zones/{OLD_ID}/dns_records")))
+        .respond_with(ResponseTemplate::new(400).set_body_json(error_body(
+            7003,
+            "Could not route to the zone, perhaps your object identifier is invalid?",
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/zones"))
+        .and(query_param("name", "example.com"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!([
+            { "id": ZONE_ID, "name": "example.com", "status": "active" }
+        ]))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", &V4.to_string())],
+            1,
+            1,
+        )))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut zones: [Zone; 1] = [toml::from_str(&format!(
+        r#"
+        name = "example.com"
+        api_url = "{}"
+        auth = {{ BearerAuth = "secret-token" }}
+        [[search]]
+        type = "A"
+        "#,
+        server.uri()
+    ))
+    .unwrap()];
+    let mut state = State::default();
+    state
+        .zone_ids
+        .insert("example.com".to_owned(), OLD_ID.to_owned());
+    let clients = Clients::new(context(), &zones);
+    resolve_zones(&mut zones, &clients, &mut state)
+        .await
+        .unwrap();
+    assert_eq!(zones[0].id(), OLD_ID);
+
+    let mut summary = RunSummary::default();
+    patch_zones(
+        &mut zones,
+        &clients,
+        (Some(V4), None),
+        FailedFamilies::default(),
+        &mut state,
+        &mut summary,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(summary.zones[0].status, ZoneStatus::Updated);
+    assert_eq!(zones[0].id(), ZONE_ID);
+    assert_eq!(state.zone_ids["example.com"], ZONE_ID);
+    assert!(state.zones.contains_key(ZONE_ID));
+}
+
+#[tokio::test]
+async fn run_verifies_credentials_unless_every_zone_is_up_to_date() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user/tokens/verify"))
+        .respond_with(
+            ResponseTemplate::new(401).set_body_json(error_body(1000, "Invalid API Token")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zones = [zone(&server, "[[search]]\ntype = \"A\"")];
+    let clients = Clients::new(context(), &zones);
+    let mut state = State::default();
+    state.update_addresses((Some(V4), None));
+    state.zones.insert(
+        ZONE_ID.to_owned(),
+        ZoneState {
+            records: Default::default(),
+            config_hash: ZoneState::config_hash(&zones[0]),
+        },
+    );
+
+    // Nothing would change, so Cloudflare is not contacted
+    let failed = FailedFamilies::default();
+    verify_credentials_for_run(&zones, &clients, &state, (Some(V4), None), failed)
+        .await
+        .unwrap();
+    // The IPv4 address is kept if its detection failed
+    let failed_v4 = FailedFamilies {
+        ipv4: true,
+        ipv6: false,
+    };
+    verify_credentials_for_run(&zones, &clients, &state, (None, None), failed_v4)
+        .await
+        .unwrap();
+
+    // A changed address has to be patched, so a wrong token fails the run up front
+    let e = verify_credentials_for_run(&zones, &clients, &state, (Some(V4), Some(V6)), failed)
+        .await
+        .unwrap_err();
+    assert!(matches!(e, Error::Auth { .. }), "{e}");
+}
+
+#[tokio::test]
+async fn zones_of_an_account_share_one_client() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(
+            "/accounts/01a7362d577a6c3019a474fd6f485823/tokens/verify",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({
+            "id": "ed17574386854bf78a67040be0a770b0",
+            "status": "active"
+        }))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/zones"))
+        .and(query_param(
+            "account.id",
+            "01a7362d577a6c3019a474fd6f485823",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!([
+            { "id": ZONE_ID, "name": "example.com", "status": "active" },
+            { "id": "023e105f4ecef8ad9ca31a8372d0c354", "name": "example.net", "status": "active" }
+        ]))))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let mut conf: Config = toml::from_str(&format!(
+        r#"
+        api_url = "{}"
+        ipv4_service = "https://api.ipify.org"
+
+        [accounts.home]
+        auth = {{ BearerAuth = "secret-token" }}
+        id = "01a7362d577a6c3019a474fd6f485823"
+
+        [[zones]]
+        name = "example.com"
+        account = "home"
+
+        [[zones]]
+        name = "example.net"
+        account = "home"
+        "#,
+        server.uri()
+    ))
+    .unwrap();
+    conf.apply_defaults();
+    conf.validate().unwrap();
+
+    let clients = Clients::new(context(), &conf.zones);
+    assert_eq!(clients.len(), 1);
+    verify_credentials(&conf.zones, &clients).await.unwrap();
+    resolve_zones(&mut conf.zones, &clients, &mut State::default())
+        .await
+        .unwrap();
+    assert_eq!(conf.zones[0].id(), ZONE_ID);
+    assert_eq!(conf.zones[1].id(), "023e105f4ecef8ad9ca31a8372d0c354");
+}
+
+#[tokio::test]
+async fn resolve_zones_fails_for_unknown_name() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/zones"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!([]))))
+        .mount(&server)
+        .await;
+
+    let mut zones: [Zone; 1] = [toml::from_str(&format!(
+        r#"
+        name = "unknown.example"
+        api_url = "{}"
+        auth = {{ BearerAuth = "secret-token" }}
+        "#,
+        server.uri()
+    ))
+    .unwrap()];
+    let clients = Clients::new(context(), &zones);
+    let e = resolve_zones(&mut zones, &clients, &mut State::default())
+        .await
+        .unwrap_err();
+    assert!(matches!(e, Error::Config(_)), "{e}");
+}
+
+#[test]
+fn ipv6_rewrite_combines_prefix_and_suffix() {
+    let rewrite = Ipv6Rewrite {
+        suffix: "::42:0:0:0:10".parse().unwrap(),
+        prefix_length: 56,
+    };
+    assert_eq!(
+        rewrite.apply("2001:db8:aa:bb01::7".parse().unwrap()),
+        "2001:db8:aa:bb42::10"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+    );
+
+    let rewrite = Ipv6Rewrite {
+        suffix: "::211:22ff:fe33:4455".parse().unwrap(),
+        prefix_length: 64,
+    };
+    assert_eq!(
+        Ipv6Rewrite::apply_to(Some(&rewrite), (Some(V4), Some(V6))),
+        (
+            Some(V4),
+            Some("2001:db8::211:22ff:fe33:4455".parse().unwrap())
+        )
+    );
+}
+
+#[tokio::test]
+async fn patch_zone_rewrites_ipv6_suffix_per_rule() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", "router.example.com"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "router.example.com", "AAAA", "2001:db8::1")],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("name", "nas.example.com"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("2", "nas.example.com", "AAAA", "2001:db8::1:10")],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_json(json!({ "content": V6.to_string() })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "router.example.com",
+            "AAAA",
+            &V6.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/2")))
+        .and(body_json(json!({ "content": "2001:db8:0:42::10" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "2",
+            "nas.example.com",
+            "AAAA",
+            "2001:db8:0:42::10",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        [[search]]
+        name = "router.example.com"
+
+        [[search]]
+        name = "nas.example.com"
+        ipv6_suffix = "::42:0:0:0:10"
+        ipv6_prefix_length = 56
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (None, Some(V6)),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.patched, 2);
+}
+
+#[test]
+fn ipv6_prefix_length_requires_suffix() {
+    let conf: Config = toml::from_str(
+        r#"
+        ipv4_service = "https://api.ipify.org"
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+        auth = { BearerAuth = "secret-token" }
+        [[zones.search]]
+        ipv6_prefix_length = 56
+        "#,
+    )
+    .unwrap();
+    assert!(matches!(conf.validate(), Err(Error::Config(_))));
+}
+
+#[tokio::test]
+async fn patch_zone_renders_templates() {
+    let server = MockServer::start().await;
+    let mut srv = record(
+        "2",
+        "_sip._udp.example.com",
+        "SRV",
+        "10 5 5060 old.example.com",
+    );
+    srv["data"] = json!({ "port": 5060, "priority": 10, "target": "old.example.com", "weight": 5 });
+    for (param, value, record) in [
+        (
+            "name",
+            "example.com",
+            record("1", "example.com", "TXT", "v=spf1 -all"),
+        ),
+        ("type", "SRV", srv),
+        (
+            "name",
+            "v6.example.com",
+            record("3", "v6.example.com", "TXT", "old"),
+        ),
+    ] {
+        Mock::given(method("GET"))
+            .and(path(RECORDS_PATH))
+            .and(query_param(param, value))
+            .respond_with(ResponseTemplate::new(200).set_body_json(list_body(vec![record], 1, 1)))
+            .mount(&server)
+            .await;
+    }
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_json(
+            json!({ "content": "v=spf1 ip4:198.51.100.7 -all" }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "example.com",
+            "TXT",
+            "v=spf1 ip4:198.51.100.7 -all",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // The whole data is sent, with only the target changed
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/2")))
+        .and(body_json(json!({ "data": {
+            "port": 5060, "priority": 10, "target": "_sip._udp.example.com", "weight": 5
+        }})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "2",
+            "_sip._udp.example.com",
+            "SRV",
+            "10 5 5060 _sip._udp.example.com",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        [[search]]
+        type = "TXT"
+        name = "example.com"
+        template = "v=spf1 ip4:{ipv4} -all"
+
+        [[search]]
+        type = "SRV"
+        template = "{name}"
+
+        [[search]]
+        type = "TXT"
+        name = "v6.example.com"
+        template = "ip6:{ipv6}"
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    // The record using the missing IPv6 address is skipped
+    assert_eq!(result.patched, 2);
+    assert!(result.errors.is_empty());
+}
+
+#[test]
+fn templates_with_unknown_placeholders_are_rejected() {
+    let conf: Config = toml::from_str(
+        r#"
+        ipv4_service = "https://api.ipify.org"
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+        auth = { BearerAuth = "secret-token" }
+
+        [[zones.search]]
+        type = "TXT"
+        template = "ip4:{ip}"
+        "#,
+    )
+    .unwrap();
+    assert!(matches!(conf.validate(), Err(Error::Config(_))));
+}
+
+#[tokio::test]
+async fn patch_zone_sets_fields_besides_content() {
+    let server = MockServer::start().await;
+    let mut current = record("2", "b.example.com", "A", &V4.to_string());
+    current["ttl"] = json!(300);
+    current["proxied"] = json!(true);
+    current["comment"] = json!("updated by dyndns at 2024-01-01T00:00:00Z");
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", &V4.to_string()), current],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_partial_json(json!({ "ttl": 300, "proxied": true })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            &V4.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // Only the timestamp of the comment would change
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/2")))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        [set]
+        ttl = 300
+
+        [[search]]
+        type = "A"
+        set = { proxied = true, comment = "updated by dyndns at {timestamp}" }
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.patched, 1);
+    assert!(result.records.contains_key("2"));
+}
+
+#[tokio::test]
+async fn patch_zone_renders_comment_template_again_with_other_changes() {
+    let server = MockServer::start().await;
+    let mut current = record("1", "a.example.com", "A", "192.0.2.1");
+    current["comment"] = json!("updated by dyndns at 2024-01-01T00:00:00Z");
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(vec![current], 1, 1)))
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_partial_json(json!({ "content": V4.to_string() })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            &V4.to_string(),
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        [[search]]
+        type = "A"
+        set = { comment = "updated by dyndns at {timestamp}" }
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(result.patched, 1);
+
+    let requests = server.received_requests().await.unwrap();
+    let patch: serde_json::Value = requests
+        .iter()
+        .find(|r| r.method.as_str() == "PATCH")
+        .unwrap()
+        .body_json()
+        .unwrap();
+    let comment = patch["comment"].as_str().unwrap();
+    assert!(comment.starts_with("updated by dyndns at "), "{comment}");
+    assert_ne!(comment, "updated by dyndns at 2024-01-01T00:00:00Z");
+}
+
+#[tokio::test]
+async fn atomic_zone_applies_changes_in_one_batch() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "A"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![record("1", "a.example.com", "A", "192.0.2.1")],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .and(query_param("type", "AAAA"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(vec![], 1, 1)))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("{RECORDS_PATH}/batch")))
+        .and(body_json(json!({
+            "patches": [{ "id": "1", "content": V4.to_string() }],
+            "posts": [{ "type": "AAAA", "name": "a.example.com", "content": V6.to_string() }],
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({
+            "patches": [record("1", "a.example.com", "A", &V4.to_string())],
+            "posts": [record("2", "a.example.com", "AAAA", &V6.to_string())],
+        }))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        atomic = true
+
+        [[search]]
+        type = "A"
+
+        [[ensure]]
+        name = "a.example.com"
+        type = "AAAA"
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), Some(V6)),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!((result.patched, result.created), (1, 1));
+    assert_eq!(result.records["2"].content, V6.to_string());
+}
+
+#[tokio::test]
+async fn failed_batch_changes_no_records() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(list_body(
+            vec![
+                record("1", "a.example.com", "A", "192.0.2.1"),
+                record("2", "b.example.com", "A", "192.0.2.1"),
+            ],
+            1,
+            1,
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(format!("{RECORDS_PATH}/batch")))
+        .respond_with(
+            ResponseTemplate::new(400).set_body_json(error_body(1004, "DNS Validation Error")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let zone = zone(
+        &server,
+        r#"
+        atomic = true
+
+        [[search]]
+        type = "A"
+        "#,
+    );
+    let result = patch_zone(
+        &zone,
+        Arc::new(client(&server)),
+        (Some(V4), None),
+        FailedFamilies::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(result.patched, 0);
+    assert_eq!(result.errors.len(), 1);
+    assert!(result.records.is_empty());
+}
@@ -0,0 +1,307 @@
+use cloudflare_dyndns::{
+    check::{check_config, check_str, Diagnostic, Severity},
+    loader::{ConfigSource, Overrides},
+};
+
+fn positions(diagnostics: &[Diagnostic]) -> Vec<(Severity, Option<usize>, Option<usize>)> {
+    diagnostics
+        .iter()
+        .map(|d| (d.severity, d.line, d.column))
+        .collect()
+}
+
+#[test]
+fn valid_config_has_no_diagnostics() {
+    let diagnostics = check_str(
+        r#"
+ipv4_service = "https://api.ipify.org"
+
+[[zones]]
+name = "example.com"
+auth = { BearerAuth = { env = "CF_API_TOKEN" } }
+
+[[zones.search]]
+type = "A"
+name = "home.example.com"
+"#,
+        &[],
+    );
+    assert_eq!(diagnostics, []);
+}
+
+#[test]
+fn parse_errors_are_located() {
+    let diagnostics = check_str(
+        r#"
+ipv4_service = "https://api.ipify.org"
+
+[[zones]]
+name = 3
+"#,
+        &[],
+    );
+    assert_eq!(
+        positions(&diagnostics),
+        [(Severity::Error, Some(5), Some(8))]
+    );
+    assert!(
+        diagnostics[0].message.contains("expected a string"),
+        "{}",
+        diagnostics[0]
+    );
+}
+
+#[test]
+fn all_problems_are_reported_with_their_position() {
+    let diagnostics = check_str(
+        r#"
+[accounts.home]
+auth = { BearerAuth = "secret-token" }
+
+[[zones]]
+name = "example.com"
+account = "work"
+
+[[zones.search]]
+type = "TXT"
+name = "example.com"
+template = "ip4:{ip}"
+
+[[zones.ensure]]
+name = "nas.example.com"
+type = "AAAA"
+ipv6_prefix_length = 56
+"#,
+        &[],
+    );
+    assert_eq!(
+        positions(&diagnostics),
+        [
+            // No address source, which concerns the whole file
+            (Severity::Error, None, None),
+            (Severity::Error, Some(7), Some(11)),
+            (Severity::Error, Some(12), Some(12)),
+            (Severity::Error, Some(17), Some(22)),
+        ]
+    );
+    assert_eq!(
+        diagnostics[1].to_string(),
+        "7:11: error: Zone \"example.com\" refers to account \"work\", which is not configured"
+    );
+}
+
+#[test]
+fn values_below_implied_tables_are_located() {
+    let diagnostics = check_str(
+        r#"
+ipv4_service.services = ["https://api.ipify.org"]
+
+[accounts.home]
+auth = 3
+"#,
+        &[],
+    );
+    assert_eq!(
+        positions(&diagnostics),
+        [(Severity::Error, Some(5), Some(8))]
+    );
+
+    let diagnostics = check_str(
+        r#"
+ipv4_service.services = []
+
+[[zones]]
+name = "example.com"
+auth = { BearerAuth = "secret-token" }
+"#,
+        &[],
+    );
+    assert_eq!(
+        positions(&diagnostics),
+        [(Severity::Error, Some(2), Some(25))]
+    );
+}
+
+#[test]
+fn quorum_has_to_be_reachable() {
+    let diagnostics = check_str(
+        r#"
+[ipv4_service]
+services = ["https://api.ipify.org", "https://ipv4.icanhazip.com"]
+strategy = "quorum"
+quorum = 3
+
+[ipv6_service]
+services = ["https://api6.ipify.org"]
+strategy = "quorum"
+quorum = 0
+
+[[zones]]
+name = "example.com"
+auth = { BearerAuth = "secret-token" }
+
+[[zones.search]]
+type = "A"
+name = "home.example.com"
+"#,
+        &[],
+    );
+    assert_eq!(
+        positions(&diagnostics),
+        [
+            (Severity::Error, Some(5), Some(10)),
+            (Severity::Error, Some(10), Some(10)),
+        ]
+    );
+    assert_eq!(
+        diagnostics[0].message,
+        "ipv4_service: quorum has to be between 1 and the number of services (2), not 3"
+    );
+}
+
+#[test]
+fn broad_rules_are_warned_about() {
+    let diagnostics = check_str(
+        r#"
+ipv4_service = "https://api.ipify.org"
+
+[[zones]]
+name = "example.com"
+auth = { BearerAuth = "secret-token" }
+
+[[zones.search]]
+proxied = true
+
+[[zones.search]]
+type = "AAAA"
+
+[[zones.search]]
+type = "TXT"
+template = "v=spf1 ip4:{ipv4} -all"
+"#,
+        &[],
+    );
+    assert_eq!(
+        positions(&diagnostics),
+        [
+            (Severity::Warning, Some(8), Some(1)),
+            (Severity::Warning, Some(14), Some(1)),
+        ]
+    );
+}
+
+#[test]
+fn problems_of_other_zones_are_left_out() {
+    let text = r#"
+[[zones]]
+name = "example.com"
+auth = { BearerAuth = "secret-token" }
+missing_family = "replace"
+
+[[zones]]
+name = "example.net"
+auth = { BearerAuth = "secret-token" }
+missing_family = "replace"
+"#;
+    assert_eq!(check_str(text, &[]).len(), 3);
+
+    let diagnostics = check_str(text, &["example.net".to_owned()]);
+    let zones: Vec<Option<&str>> = diagnostics.iter().map(|d| d.zone.as_deref()).collect();
+    // The missing address source concerns all zones
+    assert_eq!(zones, [None, Some("example.net")]);
+
+    let diagnostics = check_str(text, &["example.org".to_owned()]);
+    assert!(diagnostics
+        .iter()
+        .any(|d| d.message == "No zone \"example.org\" is configured"));
+}
+
+#[tokio::test]
+async fn problems_of_fragments_are_located_in_their_file() {
+    let dir = std::env::temp_dir().join(format!("cloudflare_dyndns-check-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("00-base.toml"),
+        r#"ipv4_service = "https://api.ipify.org"
+
+[[zones]]
+name = "example.com"
+auth = { BearerAuth = "secret-token" }
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("10-host.toml"),
+        r#"[[zones]]
+name = "example.net"
+account = "home"
+"#,
+    )
+    .unwrap();
+
+    let diagnostics = check_config(&ConfigSource::Dir(dir.clone()), &[], &[])
+        .await
+        .unwrap();
+
+    assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+    assert_eq!(diagnostics[0].file, Some(dir.join("10-host.toml")));
+    assert_eq!(diagnostics[0].zone.as_deref(), Some("example.net"));
+    assert_eq!(
+        (diagnostics[0].line, diagnostics[0].column),
+        (Some(3), Some(11))
+    );
+}
+
+#[tokio::test]
+async fn overrides_are_applied_before_checking() {
+    let dir = std::env::temp_dir().join(format!(
+        "cloudflare_dyndns-check-overrides-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        r#"[[zones]]
+name = "example.com"
+auth = { BearerAuth = "secret-token" }
+"#,
+    )
+    .unwrap();
+    let source = ConfigSource::File(config);
+
+    let diagnostics = check_config(&source, &[], &[]).await.unwrap();
+    assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+    assert_eq!(diagnostics[0].severity, Severity::Error);
+
+    let cli = Overrides {
+        ipv4_service: Some("https://api.ipify.org".to_owned()),
+        ..Default::default()
+    };
+    let diagnostics = check_config(&source, &[cli], &[]).await.unwrap();
+    assert_eq!(diagnostics, []);
+}
+
+#[tokio::test]
+async fn environment_is_checked_without_config_file() {
+    let missing = std::env::temp_dir().join("cloudflare_dyndns-check-missing/config.toml");
+    let env = Overrides::from_vars(
+        [
+            ("CF_DYNDNS_ZONE_ID", "023e105f4ecef8ad9ca31a8372d0c353"),
+            ("CF_DYNDNS_TOKEN", "env-token"),
+            ("CF_DYNDNS_RECORDS", "home.example.com"),
+        ]
+        .map(|(name, value)| (name.to_owned(), value.to_owned())),
+    )
+    .unwrap();
+
+    // Without a service, the zone of the environment can not be used
+    let diagnostics = check_config(&ConfigSource::File(missing), &[env], &[])
+        .await
+        .unwrap();
+    assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+    assert!(
+        diagnostics[0].message.contains("ipv4_service"),
+        "{diagnostics:?}"
+    );
+}
@@ -0,0 +1,455 @@
+mod common;
+
+use std::time::{Duration, SystemTime};
+
+use cloudflare_dyndns::{
+    client::CloudflareClient,
+    config::{ApiKey, Authorization},
+    error::Error,
+    records::{RecordPatch, RecordRequest, TypeSpecificData},
+    secret::{Secret, SecretSource},
+};
+use common::*;
+use serde_json::json;
+use wiremock::{
+    matchers::{body_json, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+#[tokio::test]
+async fn get_record() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(header("Authorization", "Bearer secret-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            "192.0.2.1",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let record = client(&server).get_record(ZONE_ID, "1").await.unwrap();
+    assert_eq!(record.name.0, "a.example.com");
+    assert_eq!(record.type_data.content(), "192.0.2.1");
+}
+
+#[tokio::test]
+async fn create_record() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .and(body_json(json!({
+            "type": "AAAA",
+            "content": "2001:db8::7",
+            "proxied": false,
+            "name": "new.example.com",
+            "comment": "created by test",
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "4",
+            "new.example.com",
+            "AAAA",
+            "2001:db8::7",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let request = RecordRequest {
+        type_data: TypeSpecificData::AAAA {
+            content: V6.to_string(),
+            proxied: Some(false),
+        },
+        name: "new.example.com".to_owned(),
+        comment: Some("created by test".to_owned()),
+        tags: None,
+        ttl: None,
+    };
+    let record = client(&server)
+        .create_record(ZONE_ID, &request)
+        .await
+        .unwrap();
+    assert_eq!(record.id.0, "4");
+}
+
+#[tokio::test]
+async fn patch_record_only_sends_set_fields() {
+    let server = MockServer::start().await;
+    Mock::given(method("PATCH"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .and(body_json(
+            json!({ "content": "198.51.100.7", "proxied": true }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            "198.51.100.7",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let patch = RecordPatch {
+        content: Some(V4.to_string()),
+        proxied: Some(true),
+        ..Default::default()
+    };
+    client(&server)
+        .patch_record(ZONE_ID, "1", &patch)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn delete_record() {
+    let server = MockServer::start().await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({ "id": "1" }))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client(&server).delete_record(ZONE_ID, "1").await.unwrap();
+}
+
+async fn get_record_error(response: ResponseTemplate) -> Error {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(response)
+        .mount(&server)
+        .await;
+
+    client(&server).get_record(ZONE_ID, "1").await.unwrap_err()
+}
+
+#[tokio::test]
+async fn error_status_is_classified() {
+    let e = get_record_error(
+        ResponseTemplate::new(401).set_body_json(error_body(10000, "Authentication error")),
+    )
+    .await;
+    assert!(matches!(&e, Error::Auth { messages } if messages[0].code == 10000));
+
+    let e = get_record_error(
+        ResponseTemplate::new(404).set_body_json(error_body(81044, "Record does not exist.")),
+    )
+    .await;
+    assert!(matches!(e, Error::NotFound { .. }), "{e}");
+
+    let e = get_record_error(ResponseTemplate::new(429).insert_header("Retry-After", "5")).await;
+    assert!(matches!(
+        e,
+        Error::RateLimited { retry_after: Some(d) } if d.as_secs() == 5
+    ));
+
+    let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+    let e = get_record_error(ResponseTemplate::new(429).insert_header("Retry-After", date)).await;
+    assert!(matches!(
+        e,
+        Error::RateLimited { retry_after: Some(d) } if d.as_secs() > 50 && d.as_secs() <= 60
+    ));
+
+    let e = get_record_error(
+        ResponseTemplate::new(429).insert_header("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT"),
+    )
+    .await;
+    assert!(matches!(
+        e,
+        Error::RateLimited {
+            retry_after: Some(Duration::ZERO)
+        }
+    ));
+
+    let e = get_record_error(ResponseTemplate::new(502).set_body_string("Bad gateway")).await;
+    assert!(
+        matches!(&e, Error::ApiError { code, messages } if code.as_u16() == 502 && messages.is_empty())
+    );
+}
+
+#[tokio::test]
+async fn error_codes_are_classified() {
+    let e = get_record_error(
+        ResponseTemplate::new(400).set_body_json(error_body(9109, "Invalid access token")),
+    )
+    .await;
+    assert!(matches!(e, Error::Auth { .. }), "{e}");
+
+    let e = get_record_error(
+        ResponseTemplate::new(200).set_body_json(error_body(1004, "DNS Validation Error")),
+    )
+    .await;
+    match e {
+        Error::ApiError { code, messages } => {
+            assert_eq!(code.as_u16(), 200);
+            assert_eq!(messages[0].code, 1004);
+        }
+        e => panic!("Expected api error, got {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn invalid_response_is_a_decode_error() {
+    let e =
+        get_record_error(ResponseTemplate::new(200).set_body_string("<html>Bad gateway</html>"))
+            .await;
+    assert!(matches!(e, Error::Decode(_)), "{e}");
+}
+
+fn a_record_request() -> RecordRequest {
+    RecordRequest {
+        type_data: TypeSpecificData::A {
+            content: V4.to_string(),
+            proxied: None,
+        },
+        name: "new.example.com".to_owned(),
+        comment: None,
+        tags: None,
+        ttl: None,
+    }
+}
+
+#[tokio::test]
+async fn transient_errors_are_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "1",
+            "a.example.com",
+            "A",
+            "192.0.2.1",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = client_with(&server, retry_context("backoff = \"10ms\"", 4));
+    client.get_record(ZONE_ID, "1").await.unwrap();
+}
+
+#[tokio::test]
+async fn retries_stop_after_max_attempts() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let client = client_with(
+        &server,
+        retry_context("max_attempts = 2\nbackoff = \"10ms\"", 4),
+    );
+    let e = client.get_record(ZONE_ID, "1").await.unwrap_err();
+    assert!(matches!(e, Error::ApiError { .. }), "{e}");
+}
+
+#[tokio::test]
+async fn rate_limited_requests_wait_for_retry_after() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(record(
+            "4",
+            "new.example.com",
+            "A",
+            "198.51.100.7",
+        ))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = client_with(&server, retry_context("backoff = \"10ms\"", 4));
+    let started = std::time::Instant::now();
+    client
+        .create_record(ZONE_ID, &a_record_request())
+        .await
+        .unwrap();
+    assert!(started.elapsed().as_secs_f64() >= 1.0);
+}
+
+#[tokio::test]
+async fn non_idempotent_requests_are_not_retried_on_server_errors() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path(RECORDS_PATH))
+        .respond_with(ResponseTemplate::new(502))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = client_with(&server, retry_context("backoff = \"10ms\"", 4));
+    client
+        .create_record(ZONE_ID, &a_record_request())
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn retry_after_beyond_deadline_is_not_waited_for() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "300"))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let client = client_with(&server, retry_context("deadline = \"5s\"", 4));
+    let e = client.get_record(ZONE_ID, "1").await.unwrap_err();
+    assert!(matches!(e, Error::RateLimited { .. }), "{e}");
+}
+
+#[tokio::test]
+async fn concurrent_requests_are_limited() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("{RECORDS_PATH}/1")))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(result_body(record("1", "a.example.com", "A", "192.0.2.1")))
+                .set_delay(Duration::from_millis(200)),
+        )
+        .expect(4)
+        .mount(&server)
+        .await;
+
+    // With two requests at a time, four requests take at least two rounds
+    let client = client_with(&server, retry_context("", 2));
+    let started = std::time::Instant::now();
+    let results = futures::future::join_all((0..4).map(|_| client.get_record(ZONE_ID, "1"))).await;
+    assert!(results.iter().all(Result::is_ok));
+    assert!(started.elapsed().as_millis() >= 400);
+}
+
+fn api_key_client(server: &MockServer) -> CloudflareClient {
+    CloudflareClient::new(
+        context(),
+        &server.uri(),
+        Authorization::ApiKey(ApiKey {
+            email: Secret::Plain("dns@example.com".to_owned()),
+            key: Secret::Plain("global-key".to_owned()),
+        }),
+    )
+}
+
+#[tokio::test]
+async fn api_key_sends_email_and_key() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user"))
+        .and(header("X-Auth-Email", "dns@example.com"))
+        .and(header("X-Auth-Key", "global-key"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(result_body(json!({ "id": "user" }))),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    api_key_client(&server).verify().await.unwrap();
+}
+
+#[tokio::test]
+async fn verify_rejects_inactive_token() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user/tokens/verify"))
+        .and(header("Authorization", "Bearer secret-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({
+            "id": "token",
+            "status": "expired",
+        }))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let e = client(&server).verify().await.unwrap_err();
+    assert!(matches!(e, Error::Auth { .. }), "{e}");
+}
+
+#[tokio::test]
+async fn verify_checks_tokens_of_the_account_and_then_of_the_user() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/accounts/account-id/tokens/verify"))
+        .respond_with(
+            ResponseTemplate::new(401).set_body_json(error_body(1000, "Invalid API Token")),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/user/tokens/verify"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(result_body(json!({
+            "id": "token",
+            "status": "active",
+        }))))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client(&server)
+        .with_account_id(Some("account-id".to_owned()))
+        .verify()
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn verify_reports_rejected_key() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/user"))
+        .respond_with(
+            ResponseTemplate::new(403).set_body_json(error_body(9103, "Unknown X-Auth-Key")),
+        )
+        .mount(&server)
+        .await;
+
+    let e = api_key_client(&server).verify().await.unwrap_err();
+    assert!(matches!(e, Error::Auth { .. }), "{e}");
+}
+
+#[tokio::test]
+async fn unread_secrets_fail_requests_without_sending_them() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let secret = Secret::Source(SecretSource::Env("CF_DYNDNS_TEST_TOKEN".to_owned()));
+    let e = secret.expose().unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Secret from environment variable CF_DYNDNS_TEST_TOKEN has not been read yet"
+    );
+
+    let client = CloudflareClient::new(context(), &server.uri(), Authorization::BearerAuth(secret));
+    let e = client.get_record(ZONE_ID, "1").await.unwrap_err();
+    assert!(matches!(e, Error::Config(_)), "{e}");
+}
@@ -0,0 +1,100 @@
+//! Fixtures shared by the tests running against a mocked Cloudflare API
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use cloudflare_dyndns::{
+    client::{ClientContext, CloudflareClient},
+    config::{Authorization, RetryConfig, Zone},
+    secret::Secret,
+};
+use serde_json::{json, Value};
+use wiremock::MockServer;
+
+pub const ZONE_ID: &str = "023e105f4ecef8ad9ca31a8372d0c353";
+pub const RECORDS_PATH: &str = "/zones/023e105f4ecef8ad9ca31a8372d0c353/dns_records";
+pub const V4: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+pub const V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7);
+
+pub fn record(id: &str, name: &str, r#type: &str, content: &str) -> Value {
+    json!({
+        "id": id,
+        "name": name,
+        "type": r#type,
+        "content": content,
+        "proxiable": true,
+        "proxied": false,
+        "ttl": 1,
+        "tags": [],
+        "comment": null,
+        "created_on": "2024-01-01T00:00:00Z",
+        "modified_on": "2024-01-01T00:00:00Z",
+        "zone_id": ZONE_ID,
+        "zone_name": "example.com",
+    })
+}
+
+pub fn list_body(records: Vec<Value>, page: u32, total_pages: u32) -> Value {
+    json!({
+        "result": records,
+        "errors": [],
+        "messages": [],
+        "success": true,
+        "result_info": {
+            "count": records.len(),
+            "page": page,
+            "per_page": 100,
+            "total_count": records.len() as u32 * total_pages,
+            "total_pages": total_pages,
+        },
+    })
+}
+
+pub fn result_body(result: Value) -> Value {
+    json!({ "result": result, "errors": [], "messages": [], "success": true })
+}
+
+pub fn error_body(code: u32, message: &str) -> Value {
+    json!({
+        "result": null,
+        "errors": [{ "code": code, "message": message }],
+        "messages": [],
+        "success": false,
+    })
+}
+
+/// Parses a zone using the mock server as api, with the given search rules and other settings appended
+pub fn zone(server: &MockServer, extra: &str) -> Zone {
+    toml::from_str(&format!(
+        r#"
+        identifier = "{ZONE_ID}"
+        api_url = "{}/"
+        auth = {{ BearerAuth = "secret-token" }}
+        {extra}
+        "#,
+        server.uri()
+    ))
+    .expect("Test zone should be valid")
+}
+
+/// Context that does not retry, so failing requests fail immediately
+pub fn context() -> ClientContext {
+    retry_context("max_attempts = 1", 4)
+}
+
+pub fn retry_context(retry: &str, max_concurrent_requests: usize) -> ClientContext {
+    let retry: RetryConfig = toml::from_str(retry).expect("Test retry config should be valid");
+    ClientContext::new(reqwest::Client::new(), retry, max_concurrent_requests)
+}
+
+pub fn client(server: &MockServer) -> CloudflareClient {
+    client_with(server, context())
+}
+
+pub fn client_with(server: &MockServer, context: ClientContext) -> CloudflareClient {
+    CloudflareClient::new(
+        context,
+        &server.uri(),
+        Authorization::BearerAuth(Secret::Plain("secret-token".to_owned())),
+    )
+}
@@ -0,0 +1,523 @@
+use std::{path::PathBuf, time::Duration};
+
+use cloudflare_dyndns::{
+    config::{
+        read_address_config, read_config, read_config_from, read_effective_config, ApiKey,
+        Authorization, Config, DEFAULT_API_URL,
+    },
+    error::Error,
+    loader::{self, ConfigSource, Overrides},
+    secret::Secret,
+};
+
+/// Writes a file into a directory of its own below the temporary directory
+fn write_temp(test: &str, name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cloudflare_dyndns-{test}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn read_config_resolves_secrets() {
+    let token_file = write_temp("secrets", "token", "file-token\n");
+    let credential = write_temp("secrets", "cf_key", "credential-key");
+    std::env::set_var("CF_DYNDNS_TEST_EMAIL", "dns@example.com");
+    std::env::set_var("CREDENTIALS_DIRECTORY", credential.parent().unwrap());
+    let config = write_temp(
+        "secrets",
+        "config.toml",
+        &format!(
+            r#"
+            ipv4_service = "https://api.ipify.org"
+
+            [[zones]]
+            identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+            auth = {{ BearerAuth = {{ file = "{}" }} }}
+
+            [[zones]]
+            identifier = "023e105f4ecef8ad9ca31a8372d0c354"
+            auth.ApiKey = {{ email = {{ env = "CF_DYNDNS_TEST_EMAIL" }}, key = {{ systemd_credential = "cf_key" }} }}
+            "#,
+            token_file.display()
+        ),
+    );
+
+    let conf = read_config(&config).await.unwrap();
+
+    match conf.zones[0].auth() {
+        Authorization::BearerAuth(token) => assert_eq!(token.expose().unwrap(), "file-token"),
+        auth => panic!("Unexpected auth {auth:?}"),
+    }
+    match conf.zones[1].auth() {
+        Authorization::ApiKey(ApiKey { email, key }) => {
+            assert_eq!(email.expose().unwrap(), "dns@example.com");
+            assert_eq!(key.expose().unwrap(), "credential-key");
+        }
+        auth => panic!("Unexpected auth {auth:?}"),
+    }
+    let debug = format!("{conf:?}");
+    assert!(!debug.contains("file-token"), "{debug}");
+    assert!(!debug.contains("dns@example.com"), "{debug}");
+    assert!(!debug.contains("credential-key"), "{debug}");
+}
+
+#[tokio::test]
+async fn read_config_fails_for_missing_secrets() {
+    let config = write_temp(
+        "missing-secrets",
+        "config.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+        auth = { BearerAuth = { env = "CF_DYNDNS_TEST_UNSET" } }
+        "#,
+    );
+    let e = read_config(&config).await.unwrap_err();
+    assert!(matches!(e, Error::Config(_)), "{e}");
+}
+
+#[tokio::test]
+async fn read_config_takes_auth_from_accounts() {
+    let config = write_temp(
+        "accounts",
+        "config.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [accounts.home]
+        auth = { BearerAuth = "account-token" }
+        id = "01a7362d577a6c3019a474fd6f485823"
+
+        [[zones]]
+        name = "example.com"
+        account = "home"
+
+        [[zones]]
+        name = "example.net"
+        account = "home"
+        auth = { BearerAuth = "zone-token" }
+        "#,
+    );
+
+    let conf = read_config(&config).await.unwrap();
+
+    assert_eq!(
+        conf.zones[0].auth(),
+        &Authorization::BearerAuth(Secret::Plain("account-token".to_owned()))
+    );
+    assert_eq!(
+        conf.zones[0].account_id.as_deref(),
+        Some("01a7362d577a6c3019a474fd6f485823")
+    );
+    // Credentials of the zone itself take precedence
+    assert_eq!(
+        conf.zones[1].auth(),
+        &Authorization::BearerAuth(Secret::Plain("zone-token".to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn read_config_rejects_unknown_accounts() {
+    let config = write_temp(
+        "unknown-account",
+        "config.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [accounts.home]
+        auth = { BearerAuth = "account-token" }
+
+        [[zones]]
+        name = "example.com"
+        account = "work"
+        "#,
+    );
+    let e = read_config(&config).await.unwrap_err();
+    assert!(matches!(&e, Error::Config(m) if m.contains("work")), "{e}");
+
+    let config = write_temp(
+        "unknown-account",
+        "no-auth.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [[zones]]
+        name = "example.com"
+        "#,
+    );
+    let e = read_config(&config).await.unwrap_err();
+    assert!(matches!(e, Error::Config(_)), "{e}");
+}
+
+#[tokio::test]
+async fn address_config_ignores_secrets_and_zones() {
+    let config = write_temp(
+        "address-config",
+        "config.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+        auth = { BearerAuth = { env = "CF_DYNDNS_TEST_UNSET" } }
+
+        [[zones]]
+        name = "example.com"
+        account = "work"
+        "#,
+    );
+    let source = ConfigSource::File(config);
+    assert!(read_config_from(&source, &[]).await.is_err());
+
+    let conf = read_address_config(&source, &[]).await.unwrap();
+    assert_eq!(conf.ipv4_service.unwrap().urls(), ["https://api.ipify.org"]);
+    assert_eq!(conf.zones.len(), 2);
+
+    // The settings of the address detection are still validated
+    let config = write_temp(
+        "address-config",
+        "no-service.toml",
+        r#"
+        [[zones]]
+        name = "example.com"
+        auth = { BearerAuth = "secret-token" }
+        "#,
+    );
+    let e = read_address_config(&ConfigSource::File(config), &[])
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&e, Error::Config(m) if m.contains("ipv4_service")),
+        "{e}"
+    );
+}
+
+#[tokio::test]
+async fn ensured_records_have_to_be_in_their_zone() {
+    let config = |names: &[&str]| {
+        let ensure: String = names
+            .iter()
+            .map(|n| format!("[[zones.ensure]]\nname = \"{n}\"\ntype = \"A\"\n"))
+            .collect();
+        let contents = format!(
+            r#"
+            ipv4_service = "https://api.ipify.org"
+
+            [[zones]]
+            name = "example.com"
+            auth = {{ BearerAuth = "secret-token" }}
+            {ensure}
+            "#
+        );
+        write_temp("ensure-names", "config.toml", &contents)
+    };
+
+    let conf = read_config(&config(&[
+        "example.com",
+        "home.example.com",
+        "Nas.Example.COM.",
+    ]))
+    .await
+    .unwrap();
+    assert_eq!(conf.zones[0].ensure.len(), 3);
+
+    for name in ["example.net", "badexample.com", "com"] {
+        let e = read_config(&config(&[name])).await.unwrap_err();
+        assert!(
+            matches!(&e, Error::Config(m) if m.contains("not example.com or a subdomain")),
+            "{name}: {e}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn select_zones_keeps_zones_by_name_or_identifier() {
+    let config = write_temp(
+        "select",
+        "config.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [[zones]]
+        name = "example.com"
+        auth = { BearerAuth = "secret-token" }
+
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c354"
+        auth = { BearerAuth = "secret-token" }
+
+        [[zones]]
+        name = "example.org"
+        auth = { BearerAuth = "secret-token" }
+        "#,
+    );
+    let mut conf = read_config(&config).await.unwrap();
+
+    let e = conf.select_zones(&["example.net".to_owned()]).unwrap_err();
+    assert!(matches!(e, Error::Config(_)), "{e}");
+    assert_eq!(conf.zones.len(), 3);
+
+    conf.select_zones(&[
+        "023e105f4ecef8ad9ca31a8372d0c354".to_owned(),
+        "example.com".to_owned(),
+    ])
+    .unwrap();
+    let names: Vec<&str> = conf.zones.iter().map(|z| z.display_name()).collect();
+    assert_eq!(names, ["example.com", "023e105f4ecef8ad9ca31a8372d0c354"]);
+}
+
+#[tokio::test]
+async fn read_config_detects_yaml_and_json() {
+    let yaml = write_temp(
+        "formats",
+        "config.yaml",
+        r#"
+ipv4_service: https://api.ipify.org
+zones:
+  - name: example.com
+    auth:
+      BearerAuth: secret-token
+    search:
+      - type: A
+        name: home.example.com
+"#,
+    );
+    let json = write_temp(
+        "formats",
+        "config.json",
+        r#"{
+            "ipv4_service": "https://api.ipify.org",
+            "zones": [{"name": "example.com", "auth": {"BearerAuth": "secret-token"}}]
+        }"#,
+    );
+
+    let conf = read_config(&yaml).await.unwrap();
+    assert_eq!(conf.zones[0].display_name(), "example.com");
+    assert_eq!(conf.zones[0].search.len(), 1);
+    let conf = read_config(&json).await.unwrap();
+    assert_eq!(conf.zones[0].display_name(), "example.com");
+
+    let broken = write_temp("formats", "broken.yml", "zones:\n  - name: [\n");
+    let e = read_config(&broken).await.unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Could not parse config file at line 3, column 1: did not find expected node content, while parsing a flow node"
+    );
+}
+
+#[tokio::test]
+async fn read_config_from_dir_merges_fragments() {
+    let base = write_temp(
+        "dir",
+        "00-base.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+        interval = "5m"
+
+        [accounts.home]
+        auth = { BearerAuth = "secret-token" }
+
+        [[zones]]
+        name = "example.com"
+        account = "home"
+        "#,
+    );
+    write_temp(
+        "dir",
+        "10-host.toml",
+        r#"
+        interval = "1m"
+
+        [[zones]]
+        name = "example.net"
+        account = "home"
+        "#,
+    );
+    write_temp("dir", "README.md", "Not a config fragment");
+
+    let conf = read_config_from(&ConfigSource::Dir(base.parent().unwrap().to_owned()), &[])
+        .await
+        .unwrap();
+
+    let names: Vec<&str> = conf.zones.iter().map(|z| z.display_name()).collect();
+    assert_eq!(names, ["example.com", "example.net"]);
+    assert_eq!(conf.interval(), Duration::from_secs(60));
+    assert!(conf.ipv4_service.is_some());
+}
+
+#[tokio::test]
+async fn invalid_values_are_named_by_their_path() {
+    let config = write_temp(
+        "invalid",
+        "config.json",
+        r#"{"zones": [{"name": "example.com", "missing_family": "drop"}]}"#,
+    );
+    let e = read_config(&config).await.unwrap_err();
+    assert!(
+        e.to_string()
+            .starts_with("Invalid config at zones[0].missing_family: "),
+        "{e}"
+    );
+}
+
+#[tokio::test]
+async fn effective_config_fills_defaults_and_redacts_secrets() {
+    let config = write_temp(
+        "effective",
+        "config.toml",
+        r#"
+        ipv4_service = "https://api.ipify.org"
+
+        [accounts.home]
+        auth = { BearerAuth = "secret-token" }
+        id = "0123"
+
+        [[zones]]
+        name = "example.com"
+        account = "home"
+
+        [[zones.search]]
+        type = "TXT"
+        template = "v=spf1 ip4:{ipv4} -all"
+        set = { ttl = 300 }
+
+        [[zones]]
+        name = "example.net"
+        auth.ApiKey = { email = { env = "CF_EMAIL" }, key = "secret-key" }
+        "#,
+    );
+    let conf = read_effective_config(&ConfigSource::File(config), &[])
+        .await
+        .unwrap();
+    let mut value = serde_json::to_value(&conf).unwrap();
+    assert_eq!(value["api_url"], DEFAULT_API_URL);
+    assert_eq!(value["interval"], "5m");
+    assert_eq!(
+        value["accounts"]["home"]["auth"]["BearerAuth"],
+        "<redacted>"
+    );
+
+    let zones = &value["zones"];
+    assert_eq!(zones[0]["api_url"], DEFAULT_API_URL);
+    assert_eq!(zones[0]["auth"]["BearerAuth"], "<redacted>");
+    assert_eq!(zones[0]["missing_family"], "keep");
+    assert_eq!(zones[0]["search"][0]["template"], "v=spf1 ip4:{ipv4} -all");
+    assert_eq!(zones[0]["search"][0]["set"]["ttl"], 300);
+    assert_eq!(
+        zones[1]["auth"],
+        serde_json::json!({"ApiKey": {"email": {"env": "CF_EMAIL"}, "key": "<redacted>"}})
+    );
+
+    // The printed config can be read again
+    loader::remove_nulls(&mut value);
+    let text = toml::to_string(&value).unwrap();
+    let again: Config = toml::from_str(&text).unwrap();
+    assert_eq!(
+        again.zones[0].search[0].set.ttl,
+        conf.zones[0].search[0].set.ttl
+    );
+}
+
+fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn command_line_overrides_environment_overrides_file() {
+    let config = write_temp(
+        "precedence",
+        "config.toml",
+        r#"
+        ipv4_service = "https://file.example.com/ipv4"
+        ipv6_service = "https://file.example.com/ipv6"
+
+        [[zones]]
+        identifier = "023e105f4ecef8ad9ca31a8372d0c353"
+        auth = { BearerAuth = "file-token" }
+
+        [[zones]]
+        name = "example.net"
+        auth = { BearerAuth = "file-token" }
+        "#,
+    );
+    let env = Overrides::from_vars(vars(&[
+        ("CF_DYNDNS_IPV4_SERVICE", "https://env.example.com/ipv4"),
+        ("CF_DYNDNS_IPV6_SERVICE", "https://env.example.com/ipv6"),
+        ("CF_DYNDNS_ZONE_ID", "023e105f4ecef8ad9ca31a8372d0c353"),
+        ("CF_DYNDNS_TOKEN", "env-token"),
+        ("CF_DYNDNS_RECORDS", "a.example.com, b.example.com"),
+        ("PATH", "/usr/bin"),
+    ]))
+    .unwrap();
+    let cli = Overrides {
+        ipv4_service: Some("https://cli.example.com/ipv4".to_owned()),
+        ..Default::default()
+    };
+
+    let conf = read_config_from(&ConfigSource::File(config), &[env, cli])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        conf.ipv4_service.unwrap().urls(),
+        ["https://cli.example.com/ipv4"]
+    );
+    assert_eq!(
+        conf.ipv6_service.unwrap().urls(),
+        ["https://env.example.com/ipv6"]
+    );
+    // The zone of the environment replaces the zone of the file with the same identifier
+    assert_eq!(conf.zones.len(), 2);
+    match conf.zones[0].auth() {
+        Authorization::BearerAuth(token) => assert_eq!(token.expose().unwrap(), "env-token"),
+        auth => panic!("Unexpected auth {auth:?}"),
+    }
+    let records: Vec<_> = conf.zones[0]
+        .search
+        .iter()
+        .map(|s| s.name.as_ref().unwrap().to_string())
+        .collect();
+    assert_eq!(records, ["a.example.com", "b.example.com"]);
+}
+
+#[tokio::test]
+async fn environment_builds_config_without_file() {
+    let missing = std::env::temp_dir().join("cloudflare_dyndns-missing/config.toml");
+    let env = Overrides::from_vars(vars(&[
+        ("CF_DYNDNS_IPV4_SERVICE", "https://api.ipify.org"),
+        ("CF_DYNDNS_ZONE_ID", "023e105f4ecef8ad9ca31a8372d0c353"),
+        ("CF_DYNDNS_TOKEN", "env-token"),
+        ("CF_DYNDNS_RECORDS", "home.example.com"),
+    ]))
+    .unwrap();
+
+    let conf = read_config_from(&ConfigSource::File(missing.clone()), &[env])
+        .await
+        .unwrap();
+    assert_eq!(conf.zones[0].id(), "023e105f4ecef8ad9ca31a8372d0c353");
+
+    let e = read_config_from(&ConfigSource::File(missing), &[Overrides::default()])
+        .await
+        .unwrap_err();
+    assert!(e.to_string().starts_with("Could not "), "{e}");
+}
+
+#[test]
+fn zone_variables_have_to_be_set_together() {
+    let e = Overrides::from_vars(vars(&[
+        ("CF_DYNDNS_ZONE_ID", "023e105f4ecef8ad9ca31a8372d0c353"),
+        ("CF_DYNDNS_RECORDS", "home.example.com"),
+    ]))
+    .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "CF_DYNDNS_ZONE_ID, CF_DYNDNS_TOKEN and CF_DYNDNS_RECORDS have to be set together, CF_DYNDNS_TOKEN is not set"
+    );
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use cloudflare_dyndns::{
+    daemon::{update_until, Update},
+    error::Error,
+};
+use tokio::time::{interval, sleep, Instant};
+
+const PERIOD: Duration = Duration::from_secs(300);
+
+/// Counts its updates, failing the first `failures` of them. Each update takes `duration`.
+#[derive(Default)]
+struct FakeUpdater {
+    failures: usize,
+    duration: Duration,
+    started: usize,
+    finished: usize,
+}
+impl Update for FakeUpdater {
+    async fn update(&mut self) -> Result<(), Error> {
+        self.started += 1;
+        sleep(self.duration).await;
+        self.finished += 1;
+        match self.started <= self.failures {
+            true => Err(Error::Config("Update failed".to_owned())),
+            false => Ok(()),
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn failing_updates_do_not_end_the_daemon() {
+    let mut updater = FakeUpdater {
+        failures: 2,
+        ..Default::default()
+    };
+    let shutdown = sleep(PERIOD * 3 + PERIOD / 2);
+
+    update_until(&mut interval(PERIOD), shutdown, &mut updater).await;
+
+    // The first tick is right away, then one every period
+    assert_eq!(updater.finished, 4);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_ends_the_daemon_between_updates() {
+    let start = Instant::now();
+    let mut updater = FakeUpdater::default();
+
+    update_until(
+        &mut interval(PERIOD),
+        sleep(Duration::from_secs(1)),
+        &mut updater,
+    )
+    .await;
+
+    assert_eq!(updater.finished, 1);
+    // The daemon does not wait for the next tick
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_finishes_the_update_in_progress() {
+    let start = Instant::now();
+    let mut updater = FakeUpdater {
+        duration: Duration::from_secs(10),
+        ..Default::default()
+    };
+
+    update_until(
+        &mut interval(PERIOD),
+        sleep(Duration::from_secs(1)),
+        &mut updater,
+    )
+    .await;
+
+    assert_eq!((updater.started, updater.finished), (1, 1));
+    assert_eq!(start.elapsed(), Duration::from_secs(10));
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use cloudflare_dyndns::state::{RecordState, State, ZoneState};
+use common::*;
+use wiremock::MockServer;
+
+fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cloudflare_dyndns-{test}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn state_survives_save_and_load() {
+    let path = temp_dir("state-round-trip").join("state.json");
+    let server = MockServer::start().await;
+    let zone = zone(&server, "[[search]]\ntype = \"A\"");
+
+    let mut state = State::default();
+    state.update_addresses((Some(V4), Some(V6)));
+    let record = RecordState {
+        name: "a.example.com".to_owned(),
+        content: V4.to_string(),
+    };
+    state.zones.insert(
+        ZONE_ID.to_owned(),
+        ZoneState {
+            records: HashMap::from([("1".to_owned(), record.clone())]),
+            config_hash: ZoneState::config_hash(&zone),
+        },
+    );
+    state
+        .zone_ids
+        .insert("example.com".to_owned(), ZONE_ID.to_owned());
+
+    state.save(&path).await.unwrap();
+    // Saving again replaces the file written before
+    state.save(&path).await.unwrap();
+    let loaded = State::load(&path).await;
+
+    assert_eq!(loaded.addresses, Some((Some(V4), Some(V6))));
+    assert_eq!(loaded.zones[ZONE_ID].records["1"], record);
+    assert!(loaded.zones[ZONE_ID].is_up_to_date(&zone));
+    assert_eq!(loaded.zone_ids["example.com"], ZONE_ID);
+    assert!(!path.with_file_name("state.json.tmp").exists());
+}
+
+#[tokio::test]
+async fn unreadable_state_is_ignored() {
+    let path = temp_dir("state-unreadable").join("state.json");
+    std::fs::write(&path, "{ not json").unwrap();
+
+    let state = State::load(&path).await;
+    assert_eq!(state.addresses, None);
+    assert!(state.zones.is_empty());
+}
+
+#[tokio::test]
+async fn config_hash_only_changes_with_the_records_config() {
+    let server = MockServer::start().await;
+    let hash = |extra: &str| ZoneState::config_hash(&zone(&server, extra));
+
+    let base = hash("[[search]]\ntype = \"A\"");
+    assert_eq!(base, hash("[[search]]\ntype = \"A\""));
+    assert_ne!(base, hash("[[search]]\ntype = \"A\"\nset = { ttl = 300 }"));
+    assert_ne!(base, hash("[[search]]\ntype = \"AAAA\""));
+
+    let other_token = toml::from_str(&format!(
+        r#"
+        identifier = "{ZONE_ID}"
+        api_url = "{}/"
+        auth = {{ BearerAuth = "other-token" }}
+        [[search]]
+        type = "A"
+        "#,
+        server.uri()
+    ))
+    .unwrap();
+    assert_eq!(base, ZoneState::config_hash(&other_token));
+}